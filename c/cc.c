@@ -2,7 +2,10 @@
 
 int main(){
     Writer w;
-    init_db(&w);
+    if (init_db(&w) != DB_OK) {
+        printf("init_db failed: %s\n", last_error_message());
+        return 1;
+    }
     Event e;
     e.account_id = 12,
     e.strategy_id = 1;
@@ -11,7 +14,9 @@ int main(){
     e.event_type = 0;
     e.trace_id = "trace_id_0";
 
-    write_db(&w, &e);
+    if (write_db(&w, &e) != DB_OK) {
+        printf("write_db failed: %s\n", last_error_message());
+    }
     close_db(&w);
     return 0;
 }
\ No newline at end of file
@@ -28,6 +28,262 @@ typedef struct Event {
     char *trace_id;
 } Event;
 
-void init_db(Writer* db);
-void write_db(Writer *db, Event* event);
-void close_db(Writer *db);
\ No newline at end of file
+/**
+ * 带版本的事件, 以后的字段只会追加在末尾。
+ * size 必须设置为 sizeof(EventV2), fee_coin / fee_amount 可以为 NULL
+ */
+typedef struct EventV2 {
+    uint32_t size;
+    uint8_t event_type;
+    uint64_t account_id;
+    uint64_t strategy_id;
+    const char *coin;
+    const char *amount;
+    const char *trace_id;
+    const char *fee_coin;
+    const char *fee_amount;
+    /* 事件发生的时间, epoch 微秒, 0 表示没有 */
+    int64_t event_time_us;
+    /* trade 的成交价格, 数量和方向 (0 买, 1 卖), 其他类型为 NULL */
+    const char *price;
+    const char *qty;
+    uint8_t side;
+    /* SETTLE-FEE 的费率, 其他类型为 NULL */
+    const char *fee_rate;
+} EventV2;
+
+/* event_type 的取值, 其他值会被拒绝 */
+#define EVENT_TRADE 0
+#define EVENT_BALANCE_CHANGE 1
+#define EVENT_SETTLE_FEE 2
+
+/**
+ * 按类型填充 EventV2, 只保存字符串指针, write_db_v2 返回之前要保持有效
+ */
+int event_trade(EventV2 *out, uint64_t account_id, uint64_t strategy_id, const char *coin,
+                const char *amount, const char *trade_id, const char *price, const char *qty,
+                uint8_t side, int64_t event_time_us);
+int event_balance_change(EventV2 *out, uint64_t account_id, uint64_t strategy_id,
+                         const char *coin, const char *amount, const char *event_id,
+                         int64_t event_time_us);
+int event_settle_fee(EventV2 *out, uint64_t account_id, uint64_t strategy_id, const char *coin,
+                     const char *amount, const char *event_id, const char *fee_rate,
+                     int64_t event_time_us);
+
+/**
+ * 字符串字段为 NULL 或者数值为 0 时使用默认值
+ * size 必须设置为 sizeof(DbConfig), 新字段只追加在末尾, 用旧版本头文件编译的调用方按默认值处理新字段
+ * compression: 0 不压缩, 1 snappy, 2 gzip, 3 lz4, 4 zstd
+ */
+typedef struct DbConfig {
+    uint32_t size;
+    const char *root;
+    /* 文件滚动周期, 单位秒, 必须是整分钟 */
+    uint64_t rotation_interval_secs;
+    const char *bucket;
+    const char *region;
+    const char *key_prefix;
+    uint8_t compression;
+    /* 兼容 s3 的服务地址, 例如 http://127.0.0.1:9000, NULL 时使用 aws */
+    const char *endpoint;
+    /* 文件超过这个大小时使用 multipart upload, 默认 64MB */
+    uint64_t multipart_threshold_bytes;
+    /* 每个 part 的大小, 至少 5MB, 默认 16MB */
+    uint64_t multipart_part_size_bytes;
+    /* 同时上传的 part 数量, 默认 4 */
+    uint32_t upload_concurrency;
+    /* 1 不校验上传后的 ETag, 使用 SSE-KMS 的 bucket 需要设置 */
+    uint8_t skip_etag_verify;
+    /* 归档后端: 0 s3, 1 本地目录 (store_dir), 2 不归档 */
+    uint8_t store;
+    const char *store_dir;
+    /**
+     * s3 key 模板, NULL 时为 {prefix}/{date}/{hour}/{host}-{seq}.parquet,
+     * 必须包含 {host} 或者 {file}, 以及 {seq} 或者 {file}
+     * 支持 prefix date dt year month day hour minute host seq file, 例如
+     * {prefix}/dt={dt}/hour={hour}/{host}-{seq}.parquet
+     */
+    const char *key_template;
+    /* amount 列的 Decimal128 精度, 默认 38, 18 */
+    uint8_t amount_precision;
+    uint8_t amount_scale;
+    /* 每个 coin 允许的小数位, 例如 USDT:6,BTC:8, 不能大于 amount_scale */
+    const char *coin_scales;
+    /* 文件滚动的时间窗口: 1m 5m 15m 1h 1d, 不为 NULL 时代替 rotation_interval_secs */
+    const char *rotation_window;
+    /* 文件大小 (按未压缩数据估算) 或者行数超过后滚动, 0 表示不限制, 哪个先到按哪个 */
+    uint64_t max_file_bytes;
+    uint64_t max_file_rows;
+    /* 窗口结束后多少秒内由后台线程 seal 并上传, 默认 5 秒 */
+    uint64_t seal_delay_secs;
+    /* 超过这么多秒没有写入就 seal 当前文件, 0 表示不限制 */
+    uint64_t max_idle_secs;
+    /* 文件名, s3 key 和时间列的时区: UTC, Local 或者 IANA 名字 (Asia/Shanghai), NULL 时为 UTC */
+    const char *timezone;
+    /* 压缩级别, 0 使用默认级别, 只有 gzip (1-9) 和 zstd (1-22) 支持 */
+    int32_t compression_level;
+    /* 统计信息: 0 page, 1 chunk, 2 不写 */
+    uint8_t statistics;
+    /* 1 不写 account_id 和 trace_id 的 bloom filter */
+    uint8_t disable_bloom_filter;
+    /* 每个 row group 的最大行数, 0 使用默认的 1048576 */
+    uint64_t max_row_group_size;
+    /**
+     * 重复的 (event_type, trace_id) 怎么处理: 0 照常写入, 1 丢弃并返回 DB_ERR_DUPLICATE,
+     * 2 照常写入并把 duplicate 列标记为 1。只和最近 dedup_capacity 个 trace_id 比较
+     */
+    uint8_t dedup_mode;
+    /* 去重索引保留的 trace_id 数量, 0 使用默认的 1000000 */
+    uint64_t dedup_capacity;
+    /* write_db_async 队列最多缓存的事件数, 队列满时按 backpressure 处理, 0 使用默认的 65536 */
+    uint64_t async_queue_size;
+    /* write_db 排队等待写入的最大行数, 0 使用默认的 100000 */
+    uint64_t max_queue_rows;
+    /* 每一轮 group commit 最多写入的行数和字节数 (估算), 0 使用默认的 10000 行, 16MB */
+    uint64_t max_batch_rows;
+    uint64_t max_batch_bytes;
+    /* 排队达到上限或者 write_db_async 队列已满时: 0 等待, 1 最多等待 backpressure_timeout_ms 毫秒, 2 直接返回 DB_ERR_BUSY */
+    uint8_t backpressure;
+    uint64_t backpressure_timeout_ms;
+    /* 例如 127.0.0.1:9102, 在这个地址上提供 prometheus 文本格式的 metrics, 为 NULL 时不启动 */
+    const char *metrics_addr;
+    /* write_db 排队等待写入的最大字节数 (估算), write_db_async 的队列同样按这个限制, 0 使用默认的 64MB */
+    uint64_t max_queue_bytes;
+} DbConfig;
+
+/**
+ * metrics_snapshot 的结果, 调用前把 size 设置为 sizeof(MetricsSnapshot),
+ * 以后新增的字段只追加在末尾。耗时单位为微秒, xxx_sum 除以对应的次数得到平均值
+ */
+typedef struct MetricsSnapshot {
+    uint32_t size;
+    /* 成功写入的事件数, 按 event_type */
+    uint64_t events_trade;
+    uint64_t events_balance_change;
+    uint64_t events_settle_fee;
+    /* 写入失败或者被拒绝的事件数 */
+    uint64_t events_failed;
+    /* 写入 wal 的字节数 */
+    uint64_t bytes_written;
+    /* group commit 的次数和总行数 */
+    uint64_t batches;
+    uint64_t batch_rows_sum;
+    uint64_t appends;
+    uint64_t append_latency_us_sum;
+    /* 文件滚动的次数, 按原因 */
+    uint64_t files_rotated_window;
+    uint64_t files_rotated_size;
+    uint64_t files_rotated_rows;
+    uint64_t files_rotated_idle;
+    /* 上传次数 (包括重试), 失败次数以及总耗时 */
+    uint64_t upload_attempts;
+    uint64_t upload_failures;
+    uint64_t upload_latency_us_sum;
+    /* 已经 seal 等待上传的文件数 */
+    uint64_t pending_uploads;
+    /* 同 backpressure_count */
+    uint64_t backpressure;
+} MetricsSnapshot;
+
+/**
+ * 查询条件, has_xxx 为 0 时不过滤对应字段, coin 为 NULL 时不过滤 (不区分大小写),
+ * 时间范围 [start_us, end_us) 按 event_time 过滤, 没有 event_time 的行用 ingest_time, 0 表示不限制
+ */
+typedef struct EventFilter {
+    uint8_t has_account_id;
+    uint64_t account_id;
+    uint8_t has_strategy_id;
+    uint64_t strategy_id;
+    uint8_t has_event_type;
+    uint8_t event_type;
+    const char *coin;
+    int64_t start_us;
+    int64_t end_us;
+    /* 为 NULL 时不过滤, 有 bloom filter 的文件会先用 bloom filter 跳过 row group */
+    const char *trace_id;
+} EventFilter;
+
+/**
+ * 查询回调拿到的一行, 字符串只在回调期间有效, 没有的字段为 NULL / 0,
+ * 金额类字段是十进制字符串
+ */
+typedef struct QueryRow {
+    const char *file_name;
+    uint8_t event_type;
+    uint64_t account_id;
+    uint64_t strategy_id;
+    const char *coin;
+    const char *amount;
+    const char *trace_id;
+    const char *fee_coin;
+    const char *fee_amount;
+    int64_t event_time_us;
+    int64_t ingest_time_us;
+    /* trade_price 不为 NULL 时 trade_side 才有意义 */
+    const char *trade_price;
+    const char *trade_qty;
+    uint8_t trade_side;
+    const char *fee_rate;
+    /* 去重模式为 2 时, 重复写入的行为 1 */
+    uint8_t duplicate;
+} QueryRow;
+
+/* 返回非 0 时停止查询 */
+typedef int (*QueryCallback)(const QueryRow *row, void *ctx);
+
+/**
+ * 所有函数返回 0 表示成功, 负数表示失败, 失败原因通过 last_error_message 获取
+ */
+#define DB_OK 0
+#define DB_ERR_INVALID_ARGUMENT -1
+#define DB_ERR_IO -2
+#define DB_ERR_PARQUET -3
+#define DB_ERR_CLOSED -4
+#define DB_ERR_PANIC -5
+#define DB_ERR_INVALID_AMOUNT -6
+#define DB_ERR_DUPLICATE -7
+#define DB_ERR_BUSY -8
+#define DB_ERR_INTERNAL -9
+
+int init_db(Writer* db);
+int init_db_with_config(Writer *db, const DbConfig *config);
+int write_db(Writer *db, Event* event);
+int write_db_v2(Writer *db, const EventV2 *event);
+/**
+ * 一组事件在同一轮 group commit 里写入, 某个事件失败不影响其他事件。
+ * accepted: 写入成功的数量; first_failed: 第一个失败事件的下标, 全部成功时为 count (都可以为 NULL)。
+ * 返回第一个失败事件的错误码, 全部成功时返回 0
+ */
+int write_db_batch(Writer *db, const Event *events, size_t count, size_t *accepted, size_t *first_failed);
+
+/**
+ * 写完之后在后台线程调用, status 为 0 表示已经写入, 负数是错误码。
+ * 回调里不要调用 close_db, 否则会一直等待
+ */
+typedef void (*WriteCallback)(uint64_t seq, int status, void *ctx);
+/**
+ * 拷贝事件入队后立即返回, 事件里的字符串返回后就可以释放。
+ * seq 写入这条事件的序号 (从 1 开始递增, 可以为 NULL), callback 可以为 NULL。
+ * close_db 会先把队列里的事件写完
+ */
+int write_db_async(Writer *db, const EventV2 *event, WriteCallback callback, void *ctx, uint64_t *seq);
+/* 触发背压的次数: write_db 排队达到上限, 以及 write_db_async 队列已满 */
+int backpressure_count(Writer *db, uint64_t *count);
+/*
+ * persisted_seq: 已经写入的最大序号, 不会越过同一批里第一个失败的事件; failed: 失败的事件数;
+ * failed_seq: 最近一个失败的事件的序号。小于等于 persisted_seq 并且大于 failed_seq 的事件都已经写入,
+ * 更早的事件是否成功以 callback 为准
+ */
+int async_status(Writer *db, uint64_t *persisted_seq, uint64_t *failed, uint64_t *failed_seq);
+int close_db(Writer *db);
+/* 查询本地已经写完的 parquet 文件, 正在写入和已经上传删除的文件查不到; filter 为 NULL 时返回全部 */
+int query_db(Writer *db, const EventFilter *filter, QueryCallback callback, void *ctx);
+/**
+ * 查询 trace_id 是否写入过, 结果写到 exists (1 或 0); event_type 为负数时不区分类型。
+ * 先查去重索引, 再用 bloom filter 查本地已经写完的文件, 正在写入的文件只能通过去重索引查到
+ */
+int exists_trace_id(Writer *db, int event_type, const char *trace_id, uint8_t *exists);
+/* 拷贝当前的 metrics, 只写入 snapshot->size 以内的字段 */
+int metrics_snapshot(Writer *db, MetricsSnapshot *snapshot);
+/* 当前线程最近一次失败的错误信息, 没有错误时返回 NULL */
+const char *last_error_message(void);
\ No newline at end of file
@@ -0,0 +1,336 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+
+use parking_lot::{Condvar, Mutex};
+use std::time::Instant;
+
+use super::db::{Db, Record};
+use super::error::{Error, Result, OK};
+use super::group_commit::Backpressure;
+use log::error;
+
+/// 写入完成后在后台线程调用, status 为 0 表示已经写入 wal 和 parquet, 负数是错误码
+pub type WriteCallback = extern "C" fn(seq: u64, status: i32, ctx: *mut libc::c_void);
+
+// ctx 只是原样传回给调用方
+struct Callback {
+    f: WriteCallback,
+    ctx: *mut libc::c_void,
+}
+
+unsafe impl Send for Callback {}
+
+struct Pending {
+    seq: u64,
+    // 入队时估算的大小
+    size: usize,
+    record: Record,
+    callback: Option<Callback>,
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<Pending>,
+    // 队列里记录的估算大小之和
+    queued_bytes: usize,
+    next_seq: u64,
+    started: bool,
+    closed: bool,
+    // 后台线程退出后为 true
+    drained: bool,
+}
+
+/// 异步写入的环形队列, 调用方只拷贝记录入队, 后台线程按顺序交给 group commit。
+/// 序号从 1 开始递增, persisted_seq 之前 (包括) 并且大于 failed_seq 的记录都已经写入,
+/// persisted_seq 不会越过同一批里第一条失败的记录
+pub struct AsyncQueue {
+    capacity: usize,
+    // 队列里最多缓存多少字节 (估算), None 不限制
+    max_bytes: Option<usize>,
+    // 后台线程每次最多取多少条
+    batch_rows: usize,
+    // 队列满时入队怎么处理, 已经入队的记录写入时总是等待
+    backpressure: Backpressure,
+    state: Mutex<State>,
+    cond: Condvar,
+    persisted_seq: AtomicU64,
+    failed: AtomicU64,
+    // 最近一条失败的记录的序号
+    failed_seq: AtomicU64,
+    // 入队时队列已满的次数
+    throttled: AtomicU64,
+}
+
+impl AsyncQueue {
+    pub fn new(
+        capacity: usize,
+        max_bytes: Option<usize>,
+        batch_rows: usize,
+        backpressure: Backpressure,
+    ) -> Self {
+        AsyncQueue {
+            capacity: capacity.max(1),
+            max_bytes,
+            batch_rows: batch_rows.max(1),
+            backpressure,
+            state: Mutex::new(State {
+                next_seq: 1,
+                ..State::default()
+            }),
+            cond: Condvar::new(),
+            persisted_seq: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            failed_seq: AtomicU64::new(0),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    /// 入队并返回序号, 队列的条数或者字节数满时按 backpressure 等待或者返回 Busy,
+    /// 第一次调用时启动后台线程
+    pub fn push(
+        self: &Arc<Self>,
+        db: Weak<Db>,
+        record: Record,
+        callback: Option<(WriteCallback, *mut libc::c_void)>,
+    ) -> Result<u64> {
+        let mut state = self.state.lock();
+        if state.closed {
+            return Err(Error::Closed);
+        }
+        let size = record.estimated_size();
+        // 队列为空时总是接受, 否则超过 max_bytes 的单条记录永远进不来
+        let full = |state: &State| {
+            state.queue.len() >= self.capacity
+                || (!state.queue.is_empty()
+                    && matches!(self.max_bytes, Some(max) if state.queued_bytes + size > max))
+        };
+        if full(&state) {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+            let busy = |state: &State| {
+                Error::Busy(format!(
+                    "async queue is full ({} events, {} bytes)",
+                    state.queue.len(),
+                    state.queued_bytes
+                ))
+            };
+            let deadline = match self.backpressure {
+                Backpressure::Reject => return Err(busy(&state)),
+                Backpressure::Block => None,
+                Backpressure::Timeout(timeout) => Some(Instant::now() + timeout),
+            };
+            while full(&state) && !state.closed {
+                match deadline {
+                    None => self.cond.wait(&mut state),
+                    Some(deadline) => {
+                        if self.cond.wait_until(&mut state, deadline).timed_out() && full(&state) {
+                            return Err(busy(&state));
+                        }
+                    }
+                }
+            }
+            if state.closed {
+                return Err(Error::Closed);
+            }
+        }
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.queued_bytes += size;
+        state.queue.push_back(Pending {
+            seq,
+            size,
+            record,
+            callback: callback.map(|(f, ctx)| Callback { f, ctx }),
+        });
+        if !state.started {
+            state.started = true;
+            let queue = self.clone();
+            std::thread::spawn(move || flush_loop(queue, db));
+        }
+        self.cond.notify_all();
+        Ok(seq)
+    }
+
+    /// 已经写入的最大序号, 失败的记录数, 以及最近一条失败的记录的序号
+    pub fn status(&self) -> (u64, u64, u64) {
+        (
+            self.persisted_seq.load(Ordering::Acquire),
+            self.failed.load(Ordering::Acquire),
+            self.failed_seq.load(Ordering::Acquire),
+        )
+    }
+
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    /// 不再接受新的记录, 等待后台线程把队列里的记录写完
+    pub fn close(&self) {
+        let mut state = self.state.lock();
+        state.closed = true;
+        self.cond.notify_all();
+        while state.started && !state.drained {
+            self.cond.wait(&mut state);
+        }
+    }
+
+    // 最多取出 batch_rows 条记录, 队列为空并且已经关闭时返回 None
+    fn take(&self) -> Option<Vec<Pending>> {
+        let mut state = self.state.lock();
+        loop {
+            if !state.queue.is_empty() {
+                let n = state.queue.len().min(self.batch_rows);
+                let batch = state.queue.drain(..n).collect::<Vec<_>>();
+                state.queued_bytes -= batch.iter().map(|p| p.size).sum::<usize>();
+                // 唤醒等待入队的调用方
+                self.cond.notify_all();
+                return Some(batch);
+            }
+            if state.closed {
+                state.drained = true;
+                self.cond.notify_all();
+                return None;
+            }
+            self.cond.wait(&mut state);
+        }
+    }
+}
+
+// 后台线程只持有 Db 的 Weak, 等待的时候不会让 Db 无法释放
+fn flush_loop(queue: Arc<AsyncQueue>, db: Weak<Db>) {
+    while let Some(batch) = queue.take() {
+        // 这一批里第一条失败之前的最后一个序号
+        let mut persisted = None;
+        let mut failed = false;
+        let (records, callbacks): (Vec<_>, Vec<_>) = batch
+            .into_iter()
+            .map(|p| (p.record, (p.seq, p.callback)))
+            .unzip();
+        let results = match db.upgrade() {
+            // 已经返回了序号, 不能再因为背压失败
+            Some(db) => db.write_records(records, Some(Backpressure::Block)),
+            None => records.iter().map(|_| Err(Error::Closed)).collect(),
+        };
+        for ((seq, callback), ret) in callbacks.into_iter().zip(results) {
+            let status = match &ret {
+                Ok(()) => {
+                    if !failed {
+                        persisted = Some(seq);
+                    }
+                    OK
+                }
+                Err(e) => {
+                    error!("async write {} failed {:?}", seq, e);
+                    failed = true;
+                    queue.failed.fetch_add(1, Ordering::AcqRel);
+                    queue.failed_seq.fetch_max(seq, Ordering::AcqRel);
+                    e.code()
+                }
+            };
+            if let Some(callback) = callback {
+                (callback.f)(seq, status, callback.ctx);
+            }
+        }
+        if let Some(seq) = persisted {
+            queue.persisted_seq.store(seq, Ordering::Release);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Options, StoreKind};
+    use crate::db::EventV2;
+    use std::ffi::CString;
+    use std::sync::atomic::AtomicUsize;
+
+    static CALLED: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn on_written(_seq: u64, status: i32, _ctx: *mut libc::c_void) {
+        assert_eq!(status, OK);
+        CALLED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn async_write_drains_on_close() {
+        let root = std::env::temp_dir().join(format!("async_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let db = Arc::new(
+            Db::init(Options {
+                root: root.to_str().unwrap().to_string(),
+                store: StoreKind::Noop,
+                ..Options::default()
+            })
+            .unwrap(),
+        );
+        let coin = CString::new("USDT").unwrap();
+        let amount = CString::new("1").unwrap();
+        for i in 1..=100u64 {
+            let trace_id = CString::new(format!("e{}", i)).unwrap();
+            let event =
+                EventV2::balance_change(i, 1, coin.as_ptr(), amount.as_ptr(), trace_id.as_ptr(), 0);
+            let record = Record::from_event_v2(&event).unwrap();
+            let seq = db
+                .write_async(record, Some((on_written, std::ptr::null_mut())))
+                .unwrap();
+            assert_eq!(seq, i);
+        }
+        db.close_db().unwrap();
+        assert_eq!(db.async_status(), (100, 0, 0));
+        assert_eq!(CALLED.load(Ordering::SeqCst), 100);
+
+        let event = EventV2::balance_change(1, 1, coin.as_ptr(), amount.as_ptr(), coin.as_ptr(), 0);
+        let record = Record::from_event_v2(&event).unwrap();
+        assert!(matches!(db.write_async(record, None), Err(Error::Closed)));
+    }
+
+    #[test]
+    fn push_respects_max_bytes() {
+        let coin = CString::new("USDT").unwrap();
+        let amount = CString::new("1").unwrap();
+        let record = |i: u64| {
+            let trace_id = CString::new(format!("b{}", i)).unwrap();
+            let event =
+                EventV2::balance_change(i, 1, coin.as_ptr(), amount.as_ptr(), trace_id.as_ptr(), 0);
+            Record::from_event_v2(&event).unwrap()
+        };
+        let size = record(1).estimated_size();
+        let make = |backpressure| {
+            let queue = Arc::new(AsyncQueue::new(100, Some(size * 3 / 2), 100, backpressure));
+            // 不启动后台线程, 由测试自己取出
+            queue.state.lock().started = true;
+            queue
+        };
+
+        let queue = make(Backpressure::Reject);
+        assert_eq!(queue.push(Weak::new(), record(1), None).unwrap(), 1);
+        assert!(matches!(
+            queue.push(Weak::new(), record(2), None),
+            Err(Error::Busy(_))
+        ));
+        assert_eq!(queue.throttled(), 1);
+        assert_eq!(queue.take().unwrap().len(), 1);
+        assert_eq!(queue.state.lock().queued_bytes, 0);
+        assert_eq!(queue.push(Weak::new(), record(2), None).unwrap(), 2);
+
+        let queue = make(Backpressure::Timeout(std::time::Duration::from_millis(20)));
+        queue.push(Weak::new(), record(1), None).unwrap();
+        assert!(matches!(
+            queue.push(Weak::new(), record(2), None),
+            Err(Error::Busy(_))
+        ));
+
+        let queue = make(Backpressure::Block);
+        queue.push(Weak::new(), record(1), None).unwrap();
+        let taker = {
+            let queue = queue.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                queue.take().unwrap().len()
+            })
+        };
+        assert_eq!(queue.push(Weak::new(), record(2), None).unwrap(), 2);
+        assert_eq!(taker.join().unwrap(), 1);
+    }
+}
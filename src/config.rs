@@ -0,0 +1,387 @@
+use super::decimal::AmountSpec;
+use super::dedup::DedupMode;
+use super::error::{Error, Result};
+use super::group_commit::{Backpressure, Limits};
+use super::key::{KeyTemplate, Var, DEFAULT_KEY_TEMPLATE};
+use super::props::WriterOptions;
+use super::rotation::RotationPolicy;
+use super::zone::Zone;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+
+/// 通过 `init_db_with_config` 传入的配置, 字符串字段为 null 或者数值为 0 时使用默认值。
+/// size 是调用方的 sizeof(DbConfig), 新字段只追加在末尾, 旧的调用方没有的字段按默认值处理
+#[repr(C)]
+#[derive(Debug)]
+pub struct DbConfig {
+    pub size: u32,
+    // parquet 文件的本地目录
+    pub root: *const c_char,
+    // 文件滚动周期, 单位秒, 必须是整分钟
+    pub rotation_interval_secs: u64,
+    // 为 null 时读取环境变量 s3.bucketname
+    pub bucket: *const c_char,
+    // 为 null 时读取环境变量 AWS_REGION
+    pub region: *const c_char,
+    pub key_prefix: *const c_char,
+    // 0 不压缩
+    // 1 snappy
+    // 2 gzip
+    // 3 lz4
+    // 4 zstd
+    pub compression: u8,
+    // 兼容 s3 的服务地址, 例如 http://127.0.0.1:9000, 为 null 时使用 aws
+    pub endpoint: *const c_char,
+    // 文件超过这个大小时使用 multipart upload
+    pub multipart_threshold_bytes: u64,
+    // 每个 part 的大小, s3 要求至少 5MB
+    pub multipart_part_size_bytes: u64,
+    // 同时上传的 part 数量
+    pub upload_concurrency: u32,
+    // 1 不校验上传后的 ETag, 使用 SSE-KMS 的 bucket ETag 不是 md5
+    pub skip_etag_verify: u8,
+    // 0 s3
+    // 1 本地目录, 目录由 store_dir 指定
+    // 2 不归档
+    pub store: u8,
+    pub store_dir: *const c_char,
+    // s3 key 模板, 例如 {prefix}/dt={dt}/hour={hour}/{host}-{seq}.parquet,
+    // 必须包含 host 或者 file, 以及 seq 或者 file
+    // 支持 prefix date dt year month day hour minute host seq file
+    pub key_template: *const c_char,
+    // amount 列的 Decimal128 精度, 为 0 时使用 38, 18
+    pub amount_precision: u8,
+    pub amount_scale: u8,
+    // 每个 coin 允许的小数位, 例如 USDT:6,BTC:8, 不能大于 amount_scale
+    pub coin_scales: *const c_char,
+    // 文件滚动的时间窗口, 1m 5m 15m 1h 1d, 不为 null 时代替 rotation_interval_secs
+    pub rotation_window: *const c_char,
+    // 文件大小 (按未压缩数据估算) 或者行数超过后滚动, 0 表示不限制
+    pub max_file_bytes: u64,
+    pub max_file_rows: u64,
+    // 窗口结束后多少秒内由后台线程 seal 并上传, 为 0 时使用 5 秒
+    pub seal_delay_secs: u64,
+    // 超过这么多秒没有写入就 seal 当前文件, 0 表示不限制
+    pub max_idle_secs: u64,
+    // 文件名, s3 key 和时间列使用的时区: UTC, Local 或者 IANA 名字 (Asia/Shanghai), 为 null 时使用 UTC
+    pub timezone: *const c_char,
+    // 压缩级别, 0 使用默认级别, 只有 gzip (1-9) 和 zstd (1-22) 支持
+    pub compression_level: i32,
+    // 0 page, 1 chunk, 2 不写统计信息
+    pub statistics: u8,
+    // 1 不写 account_id 和 trace_id 的 bloom filter
+    pub disable_bloom_filter: u8,
+    // 每个 row group 的最大行数, 0 使用默认的 1048576
+    pub max_row_group_size: u64,
+    // 重复的 (event_type, trace_id): 0 照常写入, 1 丢弃并返回 ERR_DUPLICATE, 2 写入并标记 duplicate 列
+    pub dedup_mode: u8,
+    // 去重索引保留最近多少个 trace_id, 0 使用默认的 1000000
+    pub dedup_capacity: u64,
+    // write_db_async 队列最多缓存多少条, 队列满时按 backpressure 处理, 0 使用默认的 65536
+    pub async_queue_size: u64,
+    // write_db 排队等待写入的最大行数, 0 使用默认的 100000
+    pub max_queue_rows: u64,
+    // 每一轮 group commit 最多写多少行, 多少字节 (估算), 0 使用默认的 10000 行, 16MB
+    pub max_batch_rows: u64,
+    pub max_batch_bytes: u64,
+    // 排队达到上限或者 write_db_async 队列已满时: 0 等待, 1 最多等待 backpressure_timeout_ms, 2 直接返回 ERR_BUSY
+    pub backpressure: u8,
+    pub backpressure_timeout_ms: u64,
+    // 例如 127.0.0.1:9102, 在这个地址上提供 prometheus 文本格式的 metrics, 为 null 时不启动
+    pub metrics_addr: *const c_char,
+    // write_db 排队等待写入的最大字节数 (估算), write_db_async 的队列同样按这个限制, 0 使用默认的 64MB
+    pub max_queue_bytes: u64,
+}
+
+// s3 multipart upload 除最后一个 part 外最小 5MB
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+/// 归档使用的存储后端
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoreKind {
+    S3,
+    Local(String),
+    Noop,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub root: String,
+    pub rotation: RotationPolicy,
+    pub bucket: Option<String>,
+    pub region: Option<String>,
+    pub key_prefix: String,
+    pub writer: WriterOptions,
+    pub endpoint: Option<String>,
+    pub multipart_threshold_bytes: u64,
+    pub multipart_part_size_bytes: u64,
+    pub upload_concurrency: usize,
+    pub verify_etag: bool,
+    pub store: StoreKind,
+    pub key_template: KeyTemplate,
+    pub amount: AmountSpec,
+    pub dedup: DedupMode,
+    pub dedup_capacity: usize,
+    pub async_queue_size: usize,
+    pub limits: Limits,
+    pub metrics_addr: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            root: "./db".to_string(),
+            rotation: RotationPolicy::default(),
+            bucket: None,
+            region: None,
+            key_prefix: "experience_coupon".to_string(),
+            writer: WriterOptions::default(),
+            endpoint: None,
+            multipart_threshold_bytes: 64 * 1024 * 1024,
+            multipart_part_size_bytes: 16 * 1024 * 1024,
+            upload_concurrency: 4,
+            verify_etag: true,
+            store: StoreKind::S3,
+            key_template: KeyTemplate::parse(DEFAULT_KEY_TEMPLATE).unwrap(),
+            amount: AmountSpec::default(),
+            dedup: DedupMode::Off,
+            dedup_capacity: 1_000_000,
+            async_queue_size: 65536,
+            limits: Limits::default(),
+            metrics_addr: None,
+        }
+    }
+}
+
+// DbConfig 里某个字段结束的偏移
+fn field_end<T>(field: impl FnOnce(*const DbConfig) -> *const T) -> usize {
+    let config = std::mem::MaybeUninit::<DbConfig>::uninit();
+    let base = config.as_ptr();
+    field(base) as usize - base as usize + std::mem::size_of::<T>()
+}
+
+impl DbConfig {
+    /// 按调用方的 size 拷贝, 超出 size 的字段为 0 / null。
+    /// 第一个版本只有 root 到 compression, size 不能比它小
+    pub fn from_ptr(config: *const DbConfig) -> Result<DbConfig> {
+        use std::ptr::addr_of;
+        if config.is_null() {
+            return Err(Error::InvalidArgument("config is null".to_string()));
+        }
+        let size = unsafe { addr_of!((*config).size).read_unaligned() } as usize;
+        let min_size = field_end(|c| unsafe { addr_of!((*c).compression) });
+        if size < min_size {
+            return Err(Error::InvalidArgument(format!(
+                "DbConfig size {} is smaller than {}",
+                size, min_size
+            )));
+        }
+        let n = size.min(std::mem::size_of::<DbConfig>());
+        // 所有字段都是整数或者指针, 全 0 是合法的默认值
+        let mut copy = unsafe { std::mem::zeroed::<DbConfig>() };
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                config as *const u8,
+                &mut copy as *mut DbConfig as *mut u8,
+                n,
+            )
+        };
+        Ok(copy)
+    }
+}
+
+impl Options {
+    pub fn from_c(config: &DbConfig) -> Result<Self> {
+        let mut options = Options::default();
+        if let Some(root) = c_str(config.root)? {
+            options.root = root;
+        }
+        if config.rotation_interval_secs != 0 {
+            options.rotation.window_secs = config.rotation_interval_secs;
+        }
+        if let Some(window) = c_str(config.rotation_window)? {
+            options.rotation.window_secs = RotationPolicy::parse_window(window.as_str())?;
+        }
+        // 文件名和 key 最细到分钟, 更短的窗口会重名
+        if options.rotation.window_secs % 60 != 0 {
+            return Err(Error::InvalidArgument(format!(
+                "rotation interval {}s is not a whole number of minutes",
+                options.rotation.window_secs
+            )));
+        }
+        options.rotation.max_bytes = Some(config.max_file_bytes).filter(|v| *v != 0);
+        options.rotation.max_rows = Some(config.max_file_rows).filter(|v| *v != 0);
+        if config.seal_delay_secs != 0 {
+            options.rotation.seal_delay_secs = config.seal_delay_secs;
+        }
+        options.rotation.max_idle_secs = Some(config.max_idle_secs).filter(|v| *v != 0);
+        if let Some(timezone) = c_str(config.timezone)? {
+            options.rotation.zone = Zone::parse(timezone.as_str())?;
+        }
+        options.bucket = c_str(config.bucket)?;
+        options.region = c_str(config.region)?;
+        if let Some(prefix) = c_str(config.key_prefix)? {
+            options.key_prefix = prefix.trim_matches('/').to_string();
+        }
+        options.writer.compression =
+            WriterOptions::compression(config.compression, config.compression_level)?;
+        options.writer.statistics = WriterOptions::statistics(config.statistics)?;
+        options.writer.bloom_filter = config.disable_bloom_filter == 0;
+        if config.max_row_group_size != 0 {
+            options.writer.max_row_group_size = config.max_row_group_size as usize;
+        }
+        options.endpoint = c_str(config.endpoint)?;
+        if config.multipart_threshold_bytes != 0 {
+            options.multipart_threshold_bytes = config.multipart_threshold_bytes;
+        }
+        if config.multipart_part_size_bytes != 0 {
+            if config.multipart_part_size_bytes < MIN_PART_SIZE {
+                return Err(Error::InvalidArgument(format!(
+                    "multipart_part_size_bytes must be at least {}",
+                    MIN_PART_SIZE
+                )));
+            }
+            options.multipart_part_size_bytes = config.multipart_part_size_bytes;
+        }
+        if config.upload_concurrency != 0 {
+            options.upload_concurrency = config.upload_concurrency as usize;
+        }
+        options.verify_etag = config.skip_etag_verify == 0;
+        if let Some(template) = c_str(config.key_template)? {
+            options.key_template = KeyTemplate::parse(template.as_str())?;
+        }
+        // 多台机器写同一个 bucket, key 里没有 host 会互相覆盖
+        if !options.key_template.has(Var::Host) && !options.key_template.has(Var::File) {
+            return Err(Error::InvalidArgument(
+                "key_template must contain {host} or {file}".to_string(),
+            ));
+        }
+        // 同一个周期里会滚动出多个文件, key 里没有序号会互相覆盖
+        if !options.key_template.has(Var::Seq) && !options.key_template.has(Var::File) {
+            return Err(Error::InvalidArgument(
+                "key_template must contain {seq} or {file}".to_string(),
+            ));
+        }
+        // 小于一小时的滚动周期, key 里没有分钟会互相覆盖
+        if options.rotation.window_secs % 3600 != 0
+            && !options.key_template.has(Var::Minute)
+            && !options.key_template.has(Var::File)
+        {
+            return Err(Error::InvalidArgument(
+                "key_template must contain {minute} or {file} when rotation is shorter than an hour"
+                    .to_string(),
+            ));
+        }
+        // precision 为 0 时 precision 和 scale 都使用默认值
+        let (precision, scale) = match config.amount_precision {
+            0 => (options.amount.precision, options.amount.scale as u8),
+            precision => (precision, config.amount_scale),
+        };
+        let coin_scales = c_str(config.coin_scales)?.unwrap_or_default();
+        options.amount = AmountSpec::new(precision, scale, coin_scales.as_str())?;
+        options.dedup = DedupMode::from_u8(config.dedup_mode)?;
+        if config.dedup_capacity != 0 {
+            options.dedup_capacity = config.dedup_capacity as usize;
+        }
+        if config.async_queue_size != 0 {
+            options.async_queue_size = config.async_queue_size as usize;
+        }
+        if config.max_queue_rows != 0 {
+            options.limits.max_queue_rows = Some(config.max_queue_rows as usize);
+        }
+        if config.max_queue_bytes != 0 {
+            options.limits.max_queue_bytes = Some(config.max_queue_bytes as usize);
+        }
+        if config.max_batch_rows != 0 {
+            options.limits.max_batch_rows = Some(config.max_batch_rows as usize);
+        }
+        if config.max_batch_bytes != 0 {
+            options.limits.max_batch_bytes = Some(config.max_batch_bytes as usize);
+        }
+        options.limits.backpressure = match config.backpressure {
+            0 => Backpressure::Block,
+            1 => Backpressure::Timeout(std::time::Duration::from_millis(
+                config.backpressure_timeout_ms,
+            )),
+            2 => Backpressure::Reject,
+            other => {
+                return Err(Error::InvalidArgument(format!(
+                    "backpressure {} is not support",
+                    other
+                )))
+            }
+        };
+        options.metrics_addr = c_str(config.metrics_addr)?;
+        options.store = match config.store {
+            0 => StoreKind::S3,
+            1 => match c_str(config.store_dir)? {
+                Some(dir) => StoreKind::Local(dir),
+                None => {
+                    return Err(Error::InvalidArgument(
+                        "store_dir is required for local store".to_string(),
+                    ))
+                }
+            },
+            2 => StoreKind::Noop,
+            other => {
+                return Err(Error::InvalidArgument(format!(
+                    "store {} is not support",
+                    other
+                )))
+            }
+        };
+        Ok(options)
+    }
+}
+
+fn c_str(ptr: *const c_char) -> Result<Option<String>> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
+    let value = unsafe { CStr::from_ptr(ptr) }
+        .to_str()
+        .map_err(|e| Error::InvalidArgument(format!("config is not valid utf-8: {}", e)))?;
+    if value.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(value.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::ptr::addr_of;
+
+    #[test]
+    fn read_fields_within_caller_size() {
+        let mut config = unsafe { std::mem::zeroed::<DbConfig>() };
+        config.compression = 1;
+        config.max_queue_rows = 5;
+        assert!(DbConfig::from_ptr(&config).is_err());
+
+        // 旧的调用方只有前面的字段
+        config.size = field_end(|c| unsafe { addr_of!((*c).compression) }) as u32;
+        let old = DbConfig::from_ptr(&config).unwrap();
+        assert_eq!(old.compression, 1);
+        assert_eq!(old.max_queue_rows, 0);
+
+        config.size = std::mem::size_of::<DbConfig>() as u32;
+        assert_eq!(DbConfig::from_ptr(&config).unwrap().max_queue_rows, 5);
+    }
+
+    #[test]
+    fn key_template_needs_seq_or_file() {
+        let from_template = |template: &str| {
+            let template = CString::new(template).unwrap();
+            let mut config = unsafe { std::mem::zeroed::<DbConfig>() };
+            config.size = std::mem::size_of::<DbConfig>() as u32;
+            config.key_template = template.as_ptr();
+            Options::from_c(&config).map(|options| options.key_template)
+        };
+        assert!(from_template("{prefix}/{date}/{hour}/{host}-{seq}.parquet").is_ok());
+        assert!(from_template("{prefix}/{host}/{file}").is_ok());
+        let err = from_template("{prefix}/{date}/{hour}/{host}.parquet").unwrap_err();
+        assert!(err.to_string().contains("{seq} or {file}"));
+    }
+}
@@ -1,24 +1,32 @@
-use arrow::array::{Array, GenericStringBuilder, PrimitiveBuilder};
-use arrow::datatypes::{DataType, Field, Schema};
-use arrow_array::builder::{Float64BufferBuilder, StringBuilder, UInt64BufferBuilder};
+use arrow::array::{GenericStringBuilder, PrimitiveBuilder};
+use arrow::datatypes::Schema;
+use arrow_array::builder::{BooleanBuilder, Decimal128Builder, TimestampMicrosecondBuilder};
 use arrow_array::RecordBatch;
-use arrow_array::{ArrayRef, Float64Array, Int32Array, StringArray, UInt64Array, UInt8Array};
-use chrono::{Local, Utc};
+use arrow_array::{StringArray, UInt64Array, UInt8Array};
+use chrono::Utc;
 use parking_lot::Mutex;
 use parquet::arrow::arrow_writer::ArrowWriter;
 use parquet::file::properties::WriterProperties;
 use std::fs::{self, File};
-use std::hash::Hash;
-use std::mem::ManuallyDrop;
 use std::sync::atomic::AtomicU8;
 use std::sync::Arc;
 
-use super::s3::Uploader;
-use log::{debug, error, info, log_enabled, warn, Level};
-use rand::distributions::Alphanumeric;
-use rand::prelude::*;
-use rand::thread_rng;
-use std::collections::{HashMap, VecDeque};
+use super::async_write::{AsyncQueue, WriteCallback};
+use super::config::Options;
+use super::decimal::AmountSpec;
+use super::dedup::{DedupIndex, DedupKey, DedupMode};
+use super::error::{Error, Result};
+use super::event::{EventKind, EVENT_BALANCE_CHANGE, EVENT_SETTLE_FEE, EVENT_TRADE};
+use super::group_commit::{Backpressure, GroupCommit, Size};
+use super::manifest::{Entry, FileState, Manifest};
+use super::metrics::{self, Metrics, MetricsSnapshot};
+use super::reader::{self, EventRow, Filter};
+use super::rotation::{FileStats, Reason, RotationPolicy};
+use super::schema;
+use super::store::Uploader;
+use super::wal::{self, Wal};
+use log::{debug, error, info, warn};
+use std::collections::VecDeque;
 use std::sync::mpsc::channel;
 
 #[repr(C)]
@@ -38,87 +46,294 @@ pub struct Event {
     pub trace_id: *const libc::c_char,
 }
 
+/// 带版本的事件, 新字段只能追加在末尾。
+/// 调用方把 size 设置为 sizeof(EventV2), 用来区分调用方编译时的结构体版本
+#[repr(C)]
+#[derive(Debug)]
+pub struct EventV2 {
+    pub size: u32,
+    pub event_type: u8,
+    pub account_id: u64,
+    pub strategy_id: u64,
+    pub coin: *const libc::c_char,
+    pub amount: *const libc::c_char,
+    pub trace_id: *const libc::c_char,
+    // 手续费, 可以为 null
+    pub fee_coin: *const libc::c_char,
+    pub fee_amount: *const libc::c_char,
+    // 事件发生的时间, epoch 微秒, 0 表示没有
+    pub event_time_us: i64,
+    // trade 的成交价格, 数量和方向 (0 买, 1 卖), 其他类型为 null
+    pub price: *const libc::c_char,
+    pub qty: *const libc::c_char,
+    pub side: u8,
+    // SETTLE-FEE 的费率, 其他类型为 null
+    pub fee_rate: *const libc::c_char,
+}
+
+// EventV2 里某个字段结束的偏移, size 不小于它说明调用方的结构体里有这个字段
+fn field_end<T>(field: impl FnOnce(*const EventV2) -> *const T) -> usize {
+    let event = std::mem::MaybeUninit::<EventV2>::uninit();
+    let base = event.as_ptr();
+    field(base) as usize - base as usize + std::mem::size_of::<T>()
+}
+
+/// `Event` 校验之后的拷贝, 调用方线程里完成转换, 之后不再依赖 C 侧的指针
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub kind: EventKind,
+    pub account_id: u64,
+    pub strategy_id: u64,
+    pub coin: String,
+    pub amount: String,
+    pub trace_id: String,
+    pub fee_coin: Option<String>,
+    pub fee_amount: Option<String>,
+    pub event_time_us: Option<i64>,
+    // 进入 db 的时间, epoch 微秒
+    pub ingest_time_us: i64,
+    // 去重模式为 flag 时由 leader 标记
+    pub duplicate: bool,
+}
+
+impl Record {
+    // 排队和分批时估算的大小, 和 wal 编码的大小差不多
+    pub(crate) fn estimated_size(&self) -> usize {
+        64 + self.coin.len()
+            + self.amount.len()
+            + self.trace_id.len()
+            + self.fee_coin.as_ref().map_or(0, |v| v.len())
+            + self.fee_amount.as_ref().map_or(0, |v| v.len())
+    }
+
+    pub fn from_event(event: &Event) -> Result<Self> {
+        Ok(Record {
+            kind: EventKind::new(event.event_type, None, None, 0, None)?,
+            account_id: event.account_id,
+            strategy_id: event.strategy_id,
+            coin: c_string(event.coin, "coin")?,
+            amount: c_string(event.amount, "amount")?,
+            trace_id: c_string(event.trace_id, "trace_id")?,
+            fee_coin: None,
+            fee_amount: None,
+            event_time_us: None,
+            ingest_time_us: Utc::now().timestamp_micros(),
+            duplicate: false,
+        })
+    }
+
+    pub fn from_event_v2(event: &EventV2) -> Result<Self> {
+        use std::ptr::addr_of;
+        let size = event.size as usize;
+        let min_size = field_end(|e| unsafe { addr_of!((*e).fee_amount) });
+        if size < min_size {
+            return Err(Error::InvalidArgument(format!(
+                "EventV2 size {} is smaller than {}",
+                size, min_size
+            )));
+        }
+        // 旧的调用方没有后面追加的字段, 不能读
+        let event_time_us = if size >= field_end(|e| unsafe { addr_of!((*e).event_time_us) })
+            && event.event_time_us != 0
+        {
+            Some(event.event_time_us)
+        } else {
+            None
+        };
+        let kind = if size >= field_end(|e| unsafe { addr_of!((*e).fee_rate) }) {
+            EventKind::new(
+                event.event_type,
+                c_string_opt(event.price, "price")?,
+                c_string_opt(event.qty, "qty")?,
+                event.side,
+                c_string_opt(event.fee_rate, "fee_rate")?,
+            )?
+        } else {
+            EventKind::new(event.event_type, None, None, 0, None)?
+        };
+        let fee_coin = c_string_opt(event.fee_coin, "fee_coin")?;
+        let fee_amount = c_string_opt(event.fee_amount, "fee_amount")?;
+        if fee_amount.is_some() && fee_coin.is_none() {
+            return Err(Error::InvalidArgument(
+                "fee_coin is required with fee_amount".to_string(),
+            ));
+        }
+        Ok(Record {
+            kind,
+            account_id: event.account_id,
+            strategy_id: event.strategy_id,
+            coin: c_string(event.coin, "coin")?,
+            amount: c_string(event.amount, "amount")?,
+            trace_id: c_string(event.trace_id, "trace_id")?,
+            fee_coin,
+            fee_amount,
+            event_time_us,
+            ingest_time_us: Utc::now().timestamp_micros(),
+            duplicate: false,
+        })
+    }
+}
+
 #[derive(Default, Debug)]
 struct BatchWrite {
     event_type: PrimitiveBuilder<arrow_array::types::UInt8Type>,
     account_id: PrimitiveBuilder<arrow_array::types::UInt64Type>,
     strategy_id: PrimitiveBuilder<arrow_array::types::UInt64Type>,
     coin: GenericStringBuilder<i32>,
-    amount: GenericStringBuilder<i32>,
+    amount: Decimal128Builder,
     trace_id: GenericStringBuilder<i32>,
+    fee_coin: GenericStringBuilder<i32>,
+    fee_amount: Decimal128Builder,
+    event_time: TimestampMicrosecondBuilder,
+    ingest_time: TimestampMicrosecondBuilder,
+    trade_price: Decimal128Builder,
+    trade_qty: Decimal128Builder,
+    trade_side: PrimitiveBuilder<arrow_array::types::UInt8Type>,
+    fee_rate: Decimal128Builder,
+    duplicate: BooleanBuilder,
+    // 同一批数据编码后的 wal 记录
+    wal: Vec<u8>,
+    rows: usize,
+    // 这一批新写入的 key, 写成功之后加入去重索引
+    dedup_keys: Vec<DedupKey>,
 }
 
 impl BatchWrite {
-    fn append(&mut self, event: &Event) {
-        use std::ffi::CStr;
-        self.event_type.append_value(event.event_type);
-        self.account_id.append_value(event.account_id);
-        self.strategy_id.append_value(event.strategy_id);
-        let coin = unsafe { CStr::from_ptr(event.coin).to_str().unwrap().to_owned() };
-        //println!("append coin {}", coin.as_str());
-        self.coin.append_value(coin);
-
-        let amount = unsafe { CStr::from_ptr(event.amount).to_str().unwrap().to_owned() };
-        //println!("append amount  {}", amount.as_str());
+    // amount 解析失败时整条记录都不写入
+    fn append(&mut self, record: &Record, spec: &AmountSpec) -> Result<()> {
+        let amount = spec.parse(record.coin.as_str(), record.amount.as_str())?;
+        let fee_amount = match (&record.fee_coin, &record.fee_amount) {
+            (Some(coin), Some(amount)) => Some(spec.parse(coin.as_str(), amount.as_str())?),
+            _ => None,
+        };
+        let trade = match record.kind.trade() {
+            Some(trade) => Some((
+                spec.parse_value("price", trade.price.as_str())?,
+                spec.parse(record.coin.as_str(), trade.qty.as_str())?,
+                trade.side as u8,
+            )),
+            None => None,
+        };
+        let fee_rate = match record.kind.fee_rate() {
+            Some(fee_rate) => Some(spec.parse_value("fee_rate", fee_rate)?),
+            None => None,
+        };
+        self.event_type.append_value(record.kind.event_type());
+        self.account_id.append_value(record.account_id);
+        self.strategy_id.append_value(record.strategy_id);
+        self.coin.append_value(record.coin.as_str());
         self.amount.append_value(amount);
-        let trace_id = unsafe { CStr::from_ptr(event.trace_id).to_str().unwrap().to_owned() };
-        //println!("append trace {}", trace_id.as_str());
-        self.trace_id.append_value(trace_id);
+        self.trace_id.append_value(record.trace_id.as_str());
+        self.fee_coin.append_option(record.fee_coin.as_deref());
+        self.fee_amount.append_option(fee_amount);
+        self.event_time.append_option(record.event_time_us);
+        self.ingest_time.append_value(record.ingest_time_us);
+        self.trade_price.append_option(trade.map(|t| t.0));
+        self.trade_qty.append_option(trade.map(|t| t.1));
+        self.trade_side.append_option(trade.map(|t| t.2));
+        self.fee_rate.append_option(fee_rate);
+        self.duplicate.append_value(record.duplicate);
+        wal::encode_record(&mut self.wal, record);
+        self.rows += 1;
+        Ok(())
     }
-}
 
-unsafe impl Send for Db {}
+    fn is_empty(&self) -> bool {
+        self.wal.is_empty()
+    }
+
+    fn rows(&self) -> usize {
+        self.rows
+    }
+}
 
-unsafe impl Sync for Db {}
+// 可选字段, null 表示没有值
+pub(crate) fn c_string_opt(ptr: *const libc::c_char, field: &str) -> Result<Option<String>> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
+    c_string(ptr, field).map(Some)
+}
 
-struct Writer {
-    done: bool,
-    event: *const Event,
-    cond: Arc<parking_lot::Condvar>,
+fn c_string(ptr: *const libc::c_char, field: &str) -> Result<String> {
+    use std::ffi::CStr;
+    if ptr.is_null() {
+        return Err(Error::InvalidArgument(format!("{} is null", field)));
+    }
+    match unsafe { CStr::from_ptr(ptr) }.to_str() {
+        Ok(value) => Ok(value.to_owned()),
+        Err(e) => Err(Error::InvalidArgument(format!(
+            "{} is not valid utf-8: {}",
+            field, e
+        ))),
+    }
 }
 
 use std::sync::mpsc::{Receiver, Sender};
 pub struct Db {
-    mu: parking_lot::Mutex<()>,
-    buffer: std::ptr::NonNull<VecDeque<*const Writer>>,
-    cond: Arc<parking_lot::Condvar>,
-    log_writer: std::ptr::NonNull<ParFile>,
-    close_recv: Receiver<()>,
+    // 每个请求是同一个调用方的一组记录, 结果和记录一一对应
+    group: GroupCommit<Vec<Record>, Vec<Result<()>>>,
+    log_writer: Arc<Mutex<ParFile>>,
+    close_recv: Mutex<Receiver<()>>,
+    ticker_stop: Mutex<Sender<()>>,
+    amount: AmountSpec,
+    root: String,
+    dedup_mode: DedupMode,
+    // 去重关闭时为 None
+    dedup: Option<Mutex<DedupIndex>>,
+    async_queue: Arc<AsyncQueue>,
+    metrics: Arc<Metrics>,
+    // 已经 seal 等待上传的文件, 用来计算 pending_uploads
+    active_files: Arc<Mutex<VecDeque<String>>>,
+    // 没有配置 metrics_addr 时为 None
+    metrics_server: Mutex<Option<metrics::Server>>,
 }
 
 impl Db {
-    pub fn init(root: &str) -> Self {
-        let buffer = unsafe {
-            let buf = Box::into_raw(Box::new(VecDeque::new()));
-            std::ptr::NonNull::new_unchecked(buf)
-        };
+    pub fn init(options: Options) -> Result<Self> {
+        fs::create_dir_all(options.root.as_str())?;
 
         let (sender, rec) = channel();
-        let par_file = ParFile::new(root, sender);
+        let metrics = Arc::new(Metrics::default());
+        let mut par_file = ParFile::new(&options, sender, metrics.clone())?;
+        par_file.recover()?;
         let active_files = par_file.active_file_nams.clone();
+        let pending_files = active_files.clone();
+        let manifest = par_file.manifest.clone();
+        if !active_files.lock().is_empty() {
+            // 唤醒上传线程处理上次没有传完的文件
+            let _ = par_file.file_name_sender.send(Some(String::new()));
+        }
+
+        // 可能失败的步骤都放在启动后台线程之前, 失败时不会留下线程
+        let up = Uploader::new(&options)?.with_metrics(metrics.clone());
+        let dedup = match options.dedup {
+            DedupMode::Off => None,
+            _ => Some(Mutex::new(DedupIndex::open(
+                options.root.as_str(),
+                options.dedup_capacity,
+            )?)),
+        };
 
         let (close_send, close_recv) = channel();
 
-        let par_file = unsafe {
-            let buf = Box::into_raw(Box::new(par_file));
-            std::ptr::NonNull::new_unchecked(buf)
-        };
+        let par_file = Arc::new(Mutex::new(par_file));
+        let ticker_stop = start_ticker(par_file.clone());
 
         std::thread::spawn(move || loop {
-            {
-                if let Ok(Some(_)) = rec.recv() {
+            match rec.recv_timeout(UPLOAD_RETRY_INTERVAL) {
+                // 有新 seal 的文件, 或者定时重试之前失败的文件
+                Ok(Some(_)) | Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
                     let mut failed = Vec::new();
                     loop {
                         let mut guand = active_files.lock();
-                        if (*guand).len() == 1 {
+                        if (*guand).is_empty() {
                             break;
                         }
 
                         let file_name = (*guand).pop_front().unwrap();
                         drop(guand);
-                        let up = Uploader {};
-                        if let Err(e) = up.upload_retry(file_name.as_str()) {
-                            error!("upload error {:?}", e);
+                        if !upload_file(&up, &manifest, file_name.as_str(), true) {
                             failed.push(file_name);
                         }
                     }
@@ -126,8 +341,10 @@ impl Db {
                         let mut guand = active_files.lock();
                         (*guand).push_back(item);
                     }
-                } else {
+                }
+                _ => {
                     info!("recv close signal, start to upload all files to ");
+                    // 每个文件只尝试一次, 失败的留在 manifest 里下次启动再传
                     loop {
                         let mut guand = active_files.lock();
                         if (*guand).is_empty() {
@@ -136,168 +353,548 @@ impl Db {
 
                         let file_name = (*guand).pop_front().unwrap();
                         drop(guand);
-                        let up = Uploader {};
-                        if let Err(e) = up.upload_retry(file_name.as_str()) {
-                            let mut guand = active_files.lock();
-                            (*guand).push_back(file_name);
-                        }
+                        upload_file(&up, &manifest, file_name.as_str(), false);
                     }
                     close_send.send(()).unwrap();
                     return;
                 }
             }
         });
-        Db {
-            mu: parking_lot::Mutex::new(()),
-            buffer,
-            cond: Arc::new(parking_lot::Condvar::new()),
+        Ok(Db {
+            group: GroupCommit::new(options.limits.clone()),
             log_writer: par_file,
-            close_recv,
+            close_recv: Mutex::new(close_recv),
+            ticker_stop: Mutex::new(ticker_stop),
+            amount: options.amount.clone(),
+            root: options.root.clone(),
+            dedup_mode: options.dedup,
+            dedup,
+            async_queue: Arc::new(AsyncQueue::new(
+                options.async_queue_size,
+                options.limits.max_queue_bytes,
+                options.limits.max_batch_rows.unwrap_or(usize::MAX),
+                options.limits.backpressure,
+            )),
+            metrics,
+            active_files: pending_files,
+            metrics_server: Mutex::new(None),
+        })
+    }
+
+    /// 查询本地已经写完的文件, 上传后删除了的文件查不到
+    pub fn query<F: FnMut(EventRow) -> bool>(&self, filter: &Filter, f: F) -> Result<()> {
+        reader::query(self.root.as_str(), filter, f)
+    }
+
+    /// 先查最近写入的索引, 再用 bloom filter 查本地已经写完的文件,
+    /// event_type 为 None 时任意类型都算
+    pub fn exists_trace_id(&self, event_type: Option<u8>, trace_id: &str) -> Result<bool> {
+        if let Some(dedup) = &self.dedup {
+            let types = match event_type {
+                Some(event_type) => vec![event_type],
+                None => vec![EVENT_TRADE, EVENT_BALANCE_CHANGE, EVENT_SETTLE_FEE],
+            };
+            let dedup = dedup.lock();
+            if types.into_iter().any(|t| dedup.contains(t, trace_id)) {
+                return Ok(true);
+            }
         }
+        let filter = Filter {
+            event_type,
+            trace_id: Some(trace_id.to_string()),
+            ..Filter::default()
+        };
+        let mut found = false;
+        self.query(&filter, |_| {
+            found = true;
+            false
+        })?;
+        Ok(found)
     }
 
-    pub fn close(&self) {
-        let log_writer = unsafe { &mut *self.log_writer.as_ptr() };
-        log_writer.close();
+    /// 在 addr 上提供 prometheus 文本格式的 metrics, close 时停止
+    pub fn serve_metrics(self: &Arc<Self>, addr: &str) -> Result<()> {
+        let server = metrics::serve(addr, Arc::downgrade(self))?;
+        if let Some(old) = self.metrics_server.lock().replace(server) {
+            old.stop();
+        }
+        Ok(())
     }
 
-    pub fn close_db(&self) {
-        self.close();
-        let _ = self.close_recv.recv();
+    pub fn close(&self) -> Result<()> {
+        if let Some(server) = self.metrics_server.lock().take() {
+            server.stop();
+        }
+        // 先把异步队列里的记录写完
+        self.async_queue.close();
+        let _ = self.ticker_stop.lock().send(());
+        self.log_writer.lock().close()
     }
 
-    pub fn write(&self, event: *const Event) {
-        let wr = Writer {
-            event,
-            cond: self.cond.clone(),
-            done: false,
-        };
+    pub fn close_db(&self) -> Result<()> {
+        let ret = self.close();
+        let _ = self.close_recv.lock().recv();
+        ret
+    }
 
-        let mut _guand = self.mu.lock();
-        let bw = unsafe { &mut *self.buffer.as_ptr() };
-        bw.push_back(&wr as *const Writer);
+    pub fn write(&self, event: *const Event) -> Result<()> {
+        self.write_record(Record::from_event(unsafe { &*event })?)
+    }
 
-        while let Some(item) = bw.front() {
-            if *item == &wr as *const Writer || wr.done {
-                // 如果是头部 或者done = true
-                break;
-            } else {
-                self.cond.wait(&mut _guand);
+    /// 一组事件在同一轮 group commit 里写入, 返回每个事件的结果,
+    /// 某个事件失败不影响其他事件
+    pub fn write_batch(&self, events: &[Event]) -> Vec<Result<()>> {
+        let mut results = Vec::with_capacity(events.len());
+        let mut records = Vec::with_capacity(events.len());
+        for event in events {
+            match Record::from_event(event) {
+                Ok(record) => {
+                    records.push(record);
+                    results.push(Ok(()));
+                }
+                Err(e) => results.push(Err(e)),
             }
         }
-        if wr.done {
-            return;
+        let mut written = self.write_records(records, None).into_iter();
+        for ret in results.iter_mut().filter(|ret| ret.is_ok()) {
+            *ret = written.next().unwrap_or(Ok(()));
         }
+        results
+    }
 
-        let mut bwg = BatchWrite::default();
-        let (last_one, size) = Db::build_batch_group(bw, &mut bwg);
-        assert_eq!(bw.len(), size, "VecDeque should be empty");
-        drop(_guand); // unlock
+    pub fn write_v2(&self, event: *const EventV2) -> Result<()> {
+        self.write_record(Record::from_event_v2(unsafe { &*event })?)
+    }
+
+    /// 拷贝记录入队后立即返回序号, 写完之后调用 callback,
+    /// 也可以通过 async_status 轮询
+    pub fn write_async(
+        self: &Arc<Self>,
+        record: Record,
+        callback: Option<(WriteCallback, *mut libc::c_void)>,
+    ) -> Result<u64> {
+        self.async_queue
+            .push(Arc::downgrade(self), record, callback)
+    }
 
-        let log_writer = unsafe { &mut *self.log_writer.as_ptr() };
-        log_writer.append(&mut bwg); // not call file.sync
+    /// 已经写入的最大序号, 失败的记录数, 以及最近一条失败的记录的序号
+    pub fn async_status(&self) -> (u64, u64, u64) {
+        self.async_queue.status()
+    }
 
-        let mut _guand = self.mu.lock();
+    fn write_record(&self, record: Record) -> Result<()> {
+        self.write_records(vec![record], None)
+            .pop()
+            .unwrap_or(Ok(()))
+    }
 
-        while let Some(item) = bw.front() {
-            if *item != &wr as *const Writer {
-                let c = unsafe { &mut *(*item as *mut Writer) };
-                c.done = true;
-                c.cond.notify_all();
-            }
-            if *item == last_one {
-                bw.pop_front();
-                break;
+    /// 一组记录作为一个请求进入 group commit, 返回每条记录的结果,
+    /// backpressure 为 None 时使用配置的策略
+    pub(crate) fn write_records(
+        &self,
+        records: Vec<Record>,
+        backpressure: Option<Backpressure>,
+    ) -> Vec<Result<()>> {
+        let size = Size {
+            rows: records.len(),
+            bytes: records.iter().map(|r| r.estimated_size()).sum(),
+        };
+        let rows = records.len();
+        let commit = |requests| self.commit_group(requests);
+        let ret = match backpressure {
+            Some(backpressure) => self.group.submit_with(records, size, backpressure, commit),
+            None => self.group.submit(records, size, commit),
+        };
+        match ret {
+            Ok(results) => results,
+            Err(e) => {
+                self.metrics.record_failed(rows as u64);
+                (0..rows).map(|_| Err(e.clone())).collect()
             }
-            bw.pop_front();
         }
-        if !bw.is_empty() {
-            let front = bw.front().unwrap();
-            let c = unsafe { &mut *(*front as *mut Writer) };
-            c.cond.notify_one();
+    }
+
+    /// 触发背压的次数: 同步写入排队达到上限, 以及异步队列已满
+    pub fn backpressure_count(&self) -> u64 {
+        self.group.throttled() + self.async_queue.throttled()
+    }
+
+    /// 当前的计数器, 以及等待上传的文件数和背压次数
+    pub fn metrics_snapshot(&self) -> MetricsSnapshot {
+        self.metrics
+            .snapshot(self.pending_uploads(), self.backpressure_count())
+    }
+
+    /// prometheus 文本格式的 metrics
+    pub fn metrics_text(&self) -> String {
+        self.metrics
+            .render(self.pending_uploads(), self.backpressure_count())
+    }
+
+    fn pending_uploads(&self) -> u64 {
+        self.active_files.lock().len() as u64
+    }
+
+    // 只有 leader 调用, 同一时间只有一个, 这里只会和后台的 ticker 竞争 ParFile
+    fn commit_group(&self, requests: Vec<Vec<Record>>) -> Vec<Vec<Result<()>>> {
+        let mut bwg = BatchWrite::default();
+        let dedup = self.dedup.as_ref().map(|d| (self.dedup_mode, d.lock()));
+        let mut results = Db::build_batch_group(
+            &requests,
+            &mut bwg,
+            &self.amount,
+            dedup.as_ref().map(|(mode, index)| (*mode, &**index)),
+        );
+        drop(dedup);
+
+        let bytes = bwg.wal.len() as u64;
+        let start = std::time::Instant::now();
+        let ret = if bwg.is_empty() {
+            // 这一组的记录都被拒绝了
+            Ok(())
+        } else {
+            self.log_writer.lock().append(&mut bwg) // wal 已经 fsync, parquet 不 sync
+        };
+        match &ret {
+            Ok(()) => {
+                if bytes != 0 {
+                    let mut rows = [0u64; 3];
+                    for (record, _) in requests
+                        .iter()
+                        .flatten()
+                        .zip(results.iter().flatten())
+                        .filter(|(_, status)| status.is_ok())
+                    {
+                        if let Some(n) = rows.get_mut(record.kind.event_type() as usize) {
+                            *n += 1;
+                        }
+                    }
+                    self.metrics.record_append(&rows, bytes, start.elapsed());
+                }
+                if let Some(dedup) = &self.dedup {
+                    // 索引只影响去重, 写失败不影响这一批的结果
+                    if let Err(e) = dedup.lock().insert(std::mem::take(&mut bwg.dedup_keys)) {
+                        error!("dedup index update failed {:?}", e);
+                    }
+                }
+            }
+            Err(e) => {
+                // 已经被拒绝的记录保留自己的错误
+                for status in results.iter_mut().flatten() {
+                    if status.is_ok() {
+                        *status = Err(e.clone());
+                    }
+                }
+            }
         }
+        let failed = results.iter().flatten().filter(|r| r.is_err()).count();
+        self.metrics.record_failed(failed as u64);
+        results
     }
 
+    // 把这一批记录加到 BatchWrite, 返回每条记录的结果, 被拒绝的记录不写入
     fn build_batch_group(
-        deque: &mut VecDeque<*const Writer>,
+        requests: &[Vec<Record>],
         batch_writer: &mut BatchWrite,
-    ) -> (*const Writer, usize) {
-        let mut last_one = std::ptr::null();
-        let mut index = 0;
-        while let Some(item) = deque.get(index) {
-            last_one = *item;
-            let item = unsafe { &**item };
-            batch_writer.append(unsafe { &*item.event });
-            index += 1;
+        spec: &AmountSpec,
+        dedup: Option<(DedupMode, &DedupIndex)>,
+    ) -> Vec<Vec<Result<()>>> {
+        // 同一批里的重复也要识别
+        let mut pending = std::collections::HashSet::new();
+        let mut results = Vec::with_capacity(requests.len());
+        for records in requests {
+            let mut statuses = Vec::with_capacity(records.len());
+            for record in records {
+                let key = dedup.map(|_| (record.kind.event_type(), record.trace_id.clone()));
+                let duplicate = match (&key, dedup) {
+                    (Some(key), Some((_, dedup_index))) => {
+                        pending.contains(key) || dedup_index.contains(key.0, key.1.as_str())
+                    }
+                    _ => false,
+                };
+                let ret = match (dedup.map(|(mode, _)| mode), duplicate) {
+                    (Some(DedupMode::Drop), true) => Err(Error::Duplicate(format!(
+                        "event_type {} trace_id {}",
+                        record.kind.event_type(),
+                        record.trace_id
+                    ))),
+                    (Some(DedupMode::Flag), true) => batch_writer.append(
+                        &Record {
+                            duplicate: true,
+                            ..record.clone()
+                        },
+                        spec,
+                    ),
+                    _ => batch_writer.append(record, spec),
+                };
+                if let (Ok(()), Some(key)) = (&ret, key) {
+                    if !duplicate {
+                        pending.insert(key.clone());
+                        batch_writer.dedup_keys.push(key);
+                    }
+                }
+                statuses.push(ret);
+            }
+            results.push(statuses);
         }
-        return (last_one, index);
+        results
     }
 }
 
-#[cfg(target_os = "linux")]
-fn create_file(file_name: &str) -> std::fs::File {
-    use std::{fs::OpenOptions, os::unix::fs::OpenOptionsExt};
-    const O_DIRECT: i32 = 0o0040000;
-    OpenOptions::new()
-        .write(true)
-        .create(true)
-        .custom_flags(O_DIRECT)
-        .open(file_name)
-        .unwrap()
+// 后台线程检查间隔, seal 的时间最多比配置的晚这么久
+const TICK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+// 没有写入的时候也要按时 seal 过期的文件, 这样上一个窗口的文件不用等到下一次写入才上传
+fn start_ticker(par_file: Arc<Mutex<ParFile>>) -> Sender<()> {
+    let (stop, stop_recv) = channel::<()>();
+    std::thread::spawn(move || loop {
+        match stop_recv.recv_timeout(TICK_INTERVAL) {
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                if let Err(e) = par_file.lock().tick(Utc::now()) {
+                    error!("seal expired file failed {:?}", e);
+                }
+            }
+            _ => return,
+        }
+    });
+    stop
 }
 
-#[cfg(target_os = "macos")]
-fn create_file(file_name: &str) -> std::fs::File {
-    use std::fs::OpenOptions;
-    // const O_DIRECT: i32 = 0o0040000;
-    OpenOptions::new()
+// 上传失败的文件留在队列里, 没有新文件 seal 时按这个间隔重试
+const UPLOAD_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+// 上传一个已经 seal 的文件并记录到 manifest, 返回是否成功。
+// retry 为 false 时只尝试一次, close 时不能等重试
+fn upload_file(up: &Uploader, manifest: &Mutex<Manifest>, file_name: &str, retry: bool) -> bool {
+    if let Err(e) = manifest.lock().set_state(file_name, FileState::Uploading) {
+        error!("manifest update {} failed {:?}", file_name, e);
+    }
+    let ret = match retry {
+        true => up.upload_retry(file_name),
+        false => up.upload_once(file_name),
+    };
+    let (entry, ok) = match ret {
+        Ok(uploaded) => (
+            Entry {
+                state: FileState::Uploaded,
+                key: uploaded.key,
+                etag: uploaded.etag,
+                md5: uploaded.md5,
+                crc32c: uploaded.crc32c,
+            },
+            true,
+        ),
+        Err(e) => {
+            error!("upload error {:?}", e);
+            let mut entry = manifest
+                .lock()
+                .get(file_name)
+                .cloned()
+                .unwrap_or_else(|| Entry::new(FileState::Failed));
+            entry.state = FileState::Failed;
+            (entry, false)
+        }
+    };
+    if let Err(e) = manifest.lock().set(file_name, entry) {
+        error!("manifest update {} failed {:?}", file_name, e);
+    }
+    ok
+}
+
+// ArrowWriter 是带缓冲的非对齐写入, 不能用 O_DIRECT
+fn create_file(file_name: &str) -> std::io::Result<std::fs::File> {
+    std::fs::OpenOptions::new()
         .write(true)
         .create(true)
-        .append(true)
+        .truncate(true)
         .open(file_name)
-        .unwrap()
+}
+
+const QUARANTINE_DIR: &str = "quarantine";
+
+// 读取 footer, 返回文件里的行数
+fn check_footer(file_name: &str) -> Result<i64> {
+    let file = File::open(file_name)?;
+    let metadata = parquet::file::footer::parse_metadata(&file)?;
+    Ok(metadata.file_metadata().num_rows())
+}
+
+// 当前正在写入的文件
+struct ActiveFile {
+    label: String,
+    // 窗口结束时间, epoch 秒
+    window_end: i64,
+    last_write: std::time::Instant,
+    name: String,
+    writer: ArrowWriter<File>,
+    // 同一个文件的另一个句柄, seal 时用来 fsync, ArrowWriter::close 不会 sync
+    sync: File,
+    stats: FileStats,
 }
 
 pub struct ParFile {
     root: String,
-    file_map: Option<(String, ArrowWriter<File>)>,
+    wal: Wal,
+    manifest: Arc<Mutex<Manifest>>,
+    rotation: RotationPolicy,
+    file_map: Option<ActiveFile>,
     schema: Arc<Schema>,
+    props: WriterProperties,
+    amount: AmountSpec,
+    // 时间列的时区
+    timezone: String,
     suffix: u32,
     active_file_nams: Arc<Mutex<VecDeque<String>>>,
     close: AtomicU8,
     file_name_sender: Sender<Option<String>>,
+    metrics: Arc<Metrics>,
 }
 
 impl ParFile {
-    pub fn new(root: &str, file_name_sender: Sender<Option<String>>) -> Self {
-        let schema = Schema::new(vec![
-            Field::new("event_type", DataType::UInt8, false),
-            Field::new("account_id", DataType::UInt64, false),
-            Field::new("strategy_id", DataType::UInt64, false),
-            Field::new("coin", DataType::Utf8, false),
-            Field::new("amount", DataType::Utf8, false),
-            Field::new("trace_id", DataType::Utf8, false),
-        ]);
-
-        ParFile {
-            root: root.to_string(),
+    pub fn new(
+        options: &Options,
+        file_name_sender: Sender<Option<String>>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
+        let timezone = options.rotation.zone.arrow_name();
+        let schema = schema::current(&options.amount, timezone.as_str());
+
+        let props = options
+            .writer
+            .build(vec![schema::key_value(schema::CURRENT_VERSION)]);
+
+        Ok(ParFile {
+            root: options.root.clone(),
+            wal: Wal::open(options.root.as_str())?,
+            manifest: Arc::new(Mutex::new(Manifest::open(options.root.as_str())?)),
+            rotation: options.rotation.clone(),
             file_map: None,
-            schema: Arc::new(schema),
+            schema,
+            props,
+            amount: options.amount.clone(),
+            timezone,
             suffix: 1,
             active_file_nams: Arc::new(Mutex::new(VecDeque::new())),
             close: AtomicU8::new(0),
             file_name_sender,
+            metrics,
+        })
+    }
+
+    /// 启动时恢复上次进程留下的文件:
+    /// 1. 有完整 footer 的文件重新放回 active_file_nams 等待上传
+    /// 2. 没有 footer 的文件移到 quarantine 目录, 其中 wal 对应的那个文件用 wal 的数据重新生成
+    pub fn recover(&mut self) -> Result<()> {
+        let replay = self.wal.replay()?;
+        // 解析不了的记录之后的数据回放不出来, wal 重置之前留一份
+        if replay.corrupt {
+            let dir = format!("{}/{}", self.root.as_str(), QUARANTINE_DIR);
+            self.wal.quarantine(dir.as_str())?;
+        }
+        // seal 时在 close 和清空 wal 之间崩溃, 文件已经完整, 由 scan_root 放回上传队列
+        let complete = match &replay.file_name {
+            Some(file_name) => check_footer(file_name.as_str()).is_ok(),
+            None => false,
+        };
+        self.scan_root()?;
+
+        if replay.records.is_empty() || complete {
+            return self.wal.clear();
         }
+        warn!(
+            "replay {} records from wal of unfinished file {:?}",
+            replay.records.len(),
+            replay.file_name
+        );
+        let mut bwg = BatchWrite::default();
+        for record in replay.records.iter() {
+            // 只有修改了 coin 的 scale 配置才会失败
+            if let Err(e) = bwg.append(record, &self.amount) {
+                error!("drop wal record {:?} {}", record, e);
+            }
+        }
+        self.open_file(Utc::now())?;
+        // open_file 重置了 wal, 恢复过程中再次崩溃时还要能重放
+        self.wal.append(bwg.wal.as_slice())?;
+        self.write_batch(&mut bwg)?;
+        self.seal()
+    }
+
+    fn scan_root(&mut self) -> Result<()> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(self.root.as_str())? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_file() && file_name.ends_with(".parquet") {
+                names.push(file_name);
+            }
+        }
+        names.sort();
+
+        for file_name in names {
+            let name = format!("{}/{}", self.root.as_str(), file_name);
+            match check_footer(name.as_str()) {
+                Ok(num_rows) => {
+                    let mut manifest = self.manifest.lock();
+                    if let Some(FileState::Uploaded) = manifest.get(name.as_str()).map(|e| e.state)
+                    {
+                        continue;
+                    }
+                    info!("recover complete file {} rows {}", name.as_str(), num_rows);
+                    manifest.set_state(name.as_str(), FileState::Sealed)?;
+                    let mut guand = self.active_file_nams.lock();
+                    (*guand).push_back(name);
+                }
+                Err(e) => {
+                    warn!("file {} has no valid footer {:?}", name.as_str(), e);
+                    self.quarantine(file_name.as_str())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 没有 footer 的文件无法定位 row group, 只能整个移走留给人工处理
+    fn quarantine(&self, file_name: &str) -> Result<()> {
+        let dir = format!("{}/{}", self.root.as_str(), QUARANTINE_DIR);
+        fs::create_dir_all(dir.as_str())?;
+        let from = format!("{}/{}", self.root.as_str(), file_name);
+        let to = format!("{}/{}", dir.as_str(), file_name);
+        warn!("move unfinished file {} to {}", from.as_str(), to.as_str());
+        fs::rename(from.as_str(), to.as_str())?;
+        Ok(())
     }
 
-    pub fn close(&mut self) {
+    pub fn close(&mut self) -> Result<()> {
         self.close.store(1, std::sync::atomic::Ordering::SeqCst);
+        let ret = self.seal();
         let _ = self.file_name_sender.send(None);
-        if let Some(file) = self.file_map.take() {
-            let _ = file.1.close();
+        ret
+    }
+
+    // 写 footer 关闭当前文件, 交给上传线程
+    fn seal(&mut self) -> Result<()> {
+        let file = match self.file_map.take() {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        file.writer.close()?;
+        // parquet 文件落盘之后才能清空 wal
+        file.sync.sync_all()?;
+        File::open(self.root.as_str())?.sync_all()?;
+        self.wal.clear()?;
+        self.manifest
+            .lock()
+            .set_state(file.name.as_str(), FileState::Sealed)?;
+        {
+            let mut guand = self.active_file_nams.lock();
+            (*guand).push_back(file.name.clone());
         }
+        let _ = self.file_name_sender.send(Some(file.name));
+        Ok(())
     }
 
+    #[cfg(test)]
     pub fn read_file(file_name: &str) -> usize {
         use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
         use std::fs::File;
@@ -307,15 +904,15 @@ impl ParFile {
         let builder = ParquetRecordBatchReaderBuilder::try_new(file).unwrap();
         println!("Converted arrow schema is: {}", builder.schema());
 
-        let mut reader = builder.build().unwrap();
+        let reader = builder.build().unwrap();
         //  return reader.count();
         let mut size = 0;
-        while let Some(item) = reader.next() {
+        for item in reader {
             let item = item.unwrap();
             size += item.num_rows();
             // println!("record batch {:?}", item.columns())
         }
-        return size;
+        size
         // let record_batch = reader.next().unwrap().unwrap();
         // println!("Read {} records.", record_batch.num_rows());
     }
@@ -327,50 +924,111 @@ impl ParFile {
                 return name;
             }
         }
-        return "".to_string();
+        "".to_string()
+    }
+
+    fn open_file(&mut self, now: chrono::DateTime<Utc>) -> Result<()> {
+        let label = self.rotation.window_label(now);
+        let name = self.build_filename(label.as_str());
+        info!("create parquet file {} ", name.as_str());
+        let _file = create_file(name.as_str())?;
+        let sync = _file.try_clone()?;
+        let writer = ArrowWriter::try_new(_file, self.schema.clone(), Some(self.props.clone()))?;
+        self.wal.reset(name.as_str())?;
+        self.manifest
+            .lock()
+            .set_state(name.as_str(), FileState::Open)?;
+        self.file_map = Some(ActiveFile {
+            label,
+            window_end: self.rotation.window_end(now),
+            last_write: std::time::Instant::now(),
+            name,
+            writer,
+            sync,
+            stats: FileStats::default(),
+        });
+        Ok(())
     }
-    fn append(&mut self, bwg: &mut BatchWrite) {
+
+    fn append(&mut self, bwg: &mut BatchWrite) -> Result<()> {
         if self.close.load(std::sync::atomic::Ordering::SeqCst) == 1 {
             warn!("close is closestatus, ignore append");
-            return;
+            return Err(Error::Closed);
         }
-        // Local::now();
-        let now = Local::now();
-        let now = now.format("%Y%m%d_%H").to_string();
-        match &mut self.file_map {
-            Some(file) => {
-                if now.as_str() != file.0.as_str() {
-                    let mut file = self.file_map.take().unwrap();
-                    file.1.close().unwrap();
-                    let name = self.build_filename(now.as_str());
-                    {
-                        let mut guand = self.active_file_nams.lock();
-                        (*guand).push_back(name.clone());
-                    }
-                    let _file = create_file(name.as_str());
-                    let writer = ArrowWriter::try_new(_file, self.schema.clone(), None).unwrap();
-                    self.file_map = Some((now, writer));
-                }
+        let now = Utc::now();
+        let label = self.rotation.window_label(now);
+        let rotate = match &self.file_map {
+            Some(file) => self.rotation.should_rotate(
+                file.label.as_str(),
+                label.as_str(),
+                &file.stats,
+                bwg.rows() as u64,
+                bwg.wal.len() as u64,
+            ),
+            None => Some(Reason::Window),
+        };
+        if let Some(reason) = rotate {
+            debug!("rotate file by {:?}", reason);
+            // 第一次打开文件不算滚动
+            if self.file_map.is_some() {
+                self.metrics.record_rotate(reason);
             }
-            None => {
-                let name = self.build_filename(now.as_str());
-                info!("file is None, create one {} ", name.as_str());
-                {
-                    let mut guand = self.active_file_nams.lock();
-                    (*guand).push_back(name.clone());
-                }
-
-                let _file = create_file(name.as_str());
-                let writer = ArrowWriter::try_new(_file, self.schema.clone(), None).unwrap();
-                self.file_map = Some((now, writer))
+            self.seal()?;
+            self.open_file(now)?;
+        }
+        let wal_size = self.wal.size()?;
+        self.wal.append(bwg.wal.as_slice())?;
+        if let Err(e) = self.write_batch(bwg) {
+            // 没有写进 parquet 的数据返回了失败, 不能留在 wal 里被恢复出来
+            if let Err(te) = self.wal.truncate(wal_size) {
+                error!("rollback wal to {} failed {}", wal_size, te);
             }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// 由后台线程定时调用, seal 窗口已经结束或者空闲太久的文件
+    pub fn tick(&mut self, now: chrono::DateTime<Utc>) -> Result<()> {
+        if self.close.load(std::sync::atomic::Ordering::SeqCst) == 1 {
+            return Ok(());
+        }
+        let reason = match &self.file_map {
+            Some(file) => self.rotation.should_seal(
+                file.window_end,
+                file.last_write.elapsed(),
+                now.timestamp(),
+            ),
+            None => None,
+        };
+        if let Some(reason) = reason {
+            info!("seal file by {:?} without new writes", reason);
+            self.metrics.record_rotate(reason);
+            self.seal()?;
+        }
+        Ok(())
+    }
+
+    fn write_batch(&mut self, bwg: &mut BatchWrite) -> Result<()> {
+        let (precision, scale) = (self.amount.precision, self.amount.scale);
+        let decimal = |builder: &mut Decimal128Builder| {
+            builder.finish().with_precision_and_scale(precision, scale)
         };
         let event_type = bwg.event_type.finish();
         let account = bwg.account_id.finish();
         let strategy = bwg.strategy_id.finish();
         let coin = bwg.coin.finish();
-        let amount = bwg.amount.finish();
+        let amount = decimal(&mut bwg.amount)?;
         let trace = bwg.trace_id.finish();
+        let fee_coin = bwg.fee_coin.finish();
+        let fee_amount = decimal(&mut bwg.fee_amount)?;
+        let event_time = bwg.event_time.finish();
+        let ingest_time = bwg.ingest_time.finish();
+        let trade_price = decimal(&mut bwg.trade_price)?;
+        let trade_qty = decimal(&mut bwg.trade_qty)?;
+        let trade_side = bwg.trade_side.finish();
+        let fee_rate = decimal(&mut bwg.fee_rate)?;
+        let duplicate = bwg.duplicate.finish();
 
         let record_batch = RecordBatch::try_new(
             self.schema.clone(),
@@ -379,15 +1037,177 @@ impl ParFile {
                 Arc::new(UInt64Array::from(account)),
                 Arc::new(UInt64Array::from(strategy)),
                 Arc::new(StringArray::from(coin)),
-                Arc::new(StringArray::from(amount)),
+                Arc::new(amount),
                 Arc::new(StringArray::from(trace)),
+                Arc::new(fee_coin),
+                Arc::new(fee_amount),
+                Arc::new(event_time.with_timezone(self.timezone.clone())),
+                Arc::new(ingest_time.with_timezone(self.timezone.clone())),
+                Arc::new(trade_price),
+                Arc::new(trade_qty),
+                Arc::new(trade_side),
+                Arc::new(fee_rate),
+                Arc::new(duplicate),
             ],
-        )
-        .unwrap();
+        )?;
 
-        let writer = &mut self.file_map.as_mut().unwrap().1;
-        writer.write(&record_batch).expect("Writing batch failed");
+        let file = self.file_map.as_mut().unwrap();
+        file.writer.write(&record_batch)?;
+        file.stats.rows += record_batch.num_rows() as u64;
+        file.stats.bytes += bwg.wal.len() as u64;
+        file.last_write = std::time::Instant::now();
+        Ok(())
     }
 }
 
 // pub fn write_to_batch(batch: &mut )
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StoreKind;
+    use std::ffi::CString;
+
+    fn options(name: &str) -> Options {
+        let root = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        Options {
+            root: root.to_str().unwrap().to_string(),
+            store: StoreKind::Noop,
+            ..Options::default()
+        }
+    }
+
+    fn par_file(options: &Options) -> ParFile {
+        let (sender, _) = channel();
+        ParFile::new(options, sender, Arc::new(Metrics::default())).unwrap()
+    }
+
+    fn append_one(par_file: &mut ParFile, options: &Options) {
+        let coin = CString::new("USDT").unwrap();
+        let amount = CString::new("1").unwrap();
+        let trace_id = CString::new("t1").unwrap();
+        let event =
+            EventV2::balance_change(1, 1, coin.as_ptr(), amount.as_ptr(), trace_id.as_ptr(), 0);
+        let mut bwg = BatchWrite::default();
+        bwg.append(&Record::from_event_v2(&event).unwrap(), &options.amount)
+            .unwrap();
+        par_file.append(&mut bwg).unwrap();
+    }
+
+    fn parquet_files(root: &str) -> Vec<String> {
+        let mut names = fs::read_dir(root)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".parquet"))
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn recover_quarantines_file_without_footer() {
+        let options = options("recover_quarantine");
+        let root = options.root.as_str();
+        fs::write(format!("{}/broken_1.parquet", root), b"PAR1 not finished").unwrap();
+
+        let mut par_file = par_file(&options);
+        par_file.recover().unwrap();
+        assert!(parquet_files(root).is_empty());
+        assert!(
+            std::path::Path::new(&format!("{}/{}/broken_1.parquet", root, QUARANTINE_DIR)).exists()
+        );
+        assert!(par_file.active_file_nams.lock().is_empty());
+    }
+
+    #[test]
+    fn recover_requeues_complete_file() {
+        let options = options("recover_requeue");
+        {
+            let mut par_file = par_file(&options);
+            append_one(&mut par_file, &options);
+            par_file.close().unwrap();
+        }
+
+        let mut par_file = par_file(&options);
+        par_file.recover().unwrap();
+        let files = parquet_files(options.root.as_str());
+        assert_eq!(files.len(), 1);
+        let active = par_file.active_file_nams.lock();
+        assert_eq!(active.len(), 1);
+        assert!(active[0].ends_with(files[0].as_str()));
+    }
+
+    #[test]
+    fn failed_write_batch_rolls_back_wal() {
+        let options = options("append_rollback");
+        let mut par_file = par_file(&options);
+        append_one(&mut par_file, &options);
+
+        let coin = CString::new("USDT").unwrap();
+        let amount = CString::new("1").unwrap();
+        let trace_id = CString::new("t2").unwrap();
+        let event =
+            EventV2::balance_change(2, 1, coin.as_ptr(), amount.as_ptr(), trace_id.as_ptr(), 0);
+        let mut bwg = BatchWrite::default();
+        bwg.append(&Record::from_event_v2(&event).unwrap(), &options.amount)
+            .unwrap();
+        // 列长度不一致, RecordBatch 创建失败
+        bwg.coin.append_value("USDT");
+        assert!(par_file.append(&mut bwg).is_err());
+
+        let replay = par_file.wal.replay().unwrap();
+        assert_eq!(replay.records.len(), 1);
+        assert_eq!(replay.records[0].trace_id, "t1");
+    }
+
+    #[test]
+    fn recover_quarantines_corrupt_wal() {
+        let options = options("recover_corrupt_wal");
+        let root = options.root.as_str();
+        {
+            let mut par_file = par_file(&options);
+            append_one(&mut par_file, &options);
+            // 追加一条校验和正确但是解析不了的记录
+            let mut frame = vec![2, 0, 0, 0];
+            frame.extend_from_slice(&crc32fast::hash(&[1, 1]).to_le_bytes());
+            frame.extend_from_slice(&[1, 1]);
+            par_file.wal.append(frame.as_slice()).unwrap();
+        }
+
+        let mut par_file = par_file(&options);
+        par_file.recover().unwrap();
+        // 之前的记录重新生成了文件, wal 留了一份在 quarantine 里
+        let files = parquet_files(root);
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            ParFile::read_file(format!("{}/{}", root, files[0]).as_str()),
+            1
+        );
+        let quarantined = fs::read_dir(format!("{}/{}", root, QUARANTINE_DIR))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        assert!(quarantined.iter().any(|name| name.starts_with("wal.log.")));
+    }
+
+    #[test]
+    fn recover_does_not_replay_wal_of_complete_file() {
+        let options = options("recover_replay");
+        {
+            let mut par_file = par_file(&options);
+            append_one(&mut par_file, &options);
+            // 模拟 seal 时写完 footer, 还没有清空 wal 就崩溃
+            let file = par_file.file_map.take().unwrap();
+            file.writer.close().unwrap();
+        }
+
+        let mut par_file = par_file(&options);
+        par_file.recover().unwrap();
+        let files = parquet_files(options.root.as_str());
+        assert_eq!(files.len(), 1);
+        assert_eq!(par_file.active_file_nams.lock().len(), 1);
+        assert!(par_file.wal.replay().unwrap().records.is_empty());
+    }
+}
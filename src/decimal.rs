@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+
+use super::error::{Error, Result};
+
+/// amount 列的精度配置, 列类型是 Decimal128(precision, scale),
+/// 每个 coin 可以配置更小的 scale, 小数位超过 coin 的 scale 的 amount 会被拒绝
+#[derive(Debug, Clone, PartialEq)]
+pub struct AmountSpec {
+    pub precision: u8,
+    pub scale: i8,
+    coin_scales: HashMap<String, u8>,
+}
+
+impl Default for AmountSpec {
+    fn default() -> Self {
+        AmountSpec {
+            precision: 38,
+            scale: 18,
+            coin_scales: HashMap::new(),
+        }
+    }
+}
+
+impl AmountSpec {
+    /// coin_scales 格式为 `USDT:6,BTC:8`
+    pub fn new(precision: u8, scale: u8, coin_scales: &str) -> Result<Self> {
+        if precision == 0 || precision > 38 || scale > precision {
+            return Err(Error::InvalidArgument(format!(
+                "amount precision {} scale {} is not support",
+                precision, scale
+            )));
+        }
+        let mut spec = AmountSpec {
+            precision,
+            scale: scale as i8,
+            coin_scales: HashMap::new(),
+        };
+        for item in coin_scales.split(',').filter(|i| !i.trim().is_empty()) {
+            let (coin, coin_scale) = item
+                .split_once(':')
+                .and_then(|(c, s)| Some((c.trim(), s.trim().parse::<u8>().ok()?)))
+                .ok_or_else(|| {
+                    Error::InvalidArgument(format!("coin scale {} is not support", item))
+                })?;
+            if coin_scale > scale {
+                return Err(Error::InvalidArgument(format!(
+                    "coin {} scale {} is larger than amount scale {}",
+                    coin, coin_scale, scale
+                )));
+            }
+            spec.coin_scales
+                .insert(coin.to_ascii_uppercase(), coin_scale);
+        }
+        Ok(spec)
+    }
+
+    fn coin_scale(&self, coin: &str) -> u8 {
+        self.coin_scales
+            .get(coin.to_ascii_uppercase().as_str())
+            .cloned()
+            .unwrap_or(self.scale as u8)
+    }
+
+    /// 把字符串形式的 amount 转成按列 scale 放大后的整数
+    pub fn parse(&self, coin: &str, amount: &str) -> Result<i128> {
+        let name = format!("amount of {}", coin);
+        self.parse_scaled(name.as_str(), amount, self.coin_scale(coin))
+    }
+
+    /// 和 coin 无关的数值 (价格, 费率), 小数位只受列的 scale 限制
+    pub fn parse_value(&self, name: &str, value: &str) -> Result<i128> {
+        self.parse_scaled(name, value, self.scale as u8)
+    }
+
+    fn parse_scaled(&self, name: &str, amount: &str, max_scale: u8) -> Result<i128> {
+        let invalid =
+            |reason: &str| Error::InvalidAmount(format!("{} {:?} {}", name, amount, reason));
+        let (negative, digits) = match amount.as_bytes().first() {
+            Some(b'-') => (true, &amount[1..]),
+            Some(b'+') => (false, &amount[1..]),
+            _ => (false, amount),
+        };
+        let (int_part, frac_part) = match digits.split_once('.') {
+            Some((i, f)) => (i, f),
+            None => (digits, ""),
+        };
+        if int_part.is_empty() && frac_part.is_empty() {
+            return Err(invalid("is empty"));
+        }
+        if !int_part
+            .bytes()
+            .chain(frac_part.bytes())
+            .all(|b| b.is_ascii_digit())
+        {
+            return Err(invalid("is not a decimal"));
+        }
+        if frac_part.len() > max_scale as usize {
+            return Err(invalid(
+                format!("has more than {} decimal places", max_scale).as_str(),
+            ));
+        }
+
+        let mut value: i128 = 0;
+        let padding = self.scale as usize - frac_part.len();
+        for b in int_part
+            .bytes()
+            .chain(frac_part.bytes())
+            .chain(std::iter::repeat_n(b'0', padding))
+        {
+            value = value
+                .checked_mul(10)
+                .and_then(|v| v.checked_add((b - b'0') as i128))
+                .ok_or_else(|| invalid("overflows"))?;
+        }
+        if value >= 10i128.pow(self.precision as u32) {
+            return Err(invalid(
+                format!("exceeds precision {}", self.precision).as_str(),
+            ));
+        }
+        Ok(if negative { -value } else { value })
+    }
+}
+
+/// 把 Decimal128 的值转回字符串, 去掉小数末尾的 0
+pub fn format(value: i128, scale: i8) -> String {
+    if scale <= 0 {
+        return (value * 10i128.pow(scale.unsigned_abs() as u32)).to_string();
+    }
+    let digits = value.unsigned_abs().to_string();
+    let scale = scale as usize;
+    let digits = format!("{:0>width$}", digits, width = scale + 1);
+    let (int_part, frac_part) = digits.split_at(digits.len() - scale);
+    let frac_part = frac_part.trim_end_matches('0');
+    let sign = if value < 0 { "-" } else { "" };
+    if frac_part.is_empty() {
+        format!("{}{}", sign, int_part)
+    } else {
+        format!("{}{}.{}", sign, int_part, frac_part)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_amount() {
+        let spec = AmountSpec::new(20, 8, "USDT:6, btc:8").unwrap();
+        assert_eq!(spec.parse("USDT", "1234").unwrap(), 1234_0000_0000);
+        assert_eq!(spec.parse("usdt", "-0.5").unwrap(), -5000_0000);
+        assert_eq!(spec.parse("BTC", ".00000001").unwrap(), 1);
+        assert_eq!(spec.parse("ETH", "1.12345678").unwrap(), 1_1234_5678);
+        assert_eq!(spec.parse_value("fee_rate", "0.0002").unwrap(), 2_0000);
+
+        assert!(spec.parse("USDT", "0.1234567").is_err());
+        assert!(spec.parse("USDT", "12a").is_err());
+        assert!(spec.parse("USDT", "").is_err());
+        assert!(spec.parse("USDT", "-").is_err());
+        assert!(spec.parse("USDT", "1e5").is_err());
+        // 整数部分最多 20 - 8 = 12 位
+        assert!(spec.parse("USDT", "999999999999").is_ok());
+        assert!(spec.parse("USDT", "1000000000000").is_err());
+    }
+
+    #[test]
+    fn format_amount() {
+        assert_eq!(format(1234_0000_0000, 8), "1234");
+        assert_eq!(format(-5000_0000, 8), "-0.5");
+        assert_eq!(format(1, 8), "0.00000001");
+        assert_eq!(format(12, 0), "12");
+    }
+
+    #[test]
+    fn reject_bad_spec() {
+        assert!(AmountSpec::new(39, 8, "").is_err());
+        assert!(AmountSpec::new(20, 8, "USDT:9").is_err());
+        assert!(AmountSpec::new(20, 8, "USDT").is_err());
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use super::error::{Error, Result};
+use log::warn;
+
+const DEDUP_FILE: &str = "dedup.log";
+
+/// 重复的 (event_type, trace_id) 怎么处理
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DedupMode {
+    // 不去重, 每条都写入
+    #[default]
+    Off,
+    // 不写入, 返回 ERR_DUPLICATE
+    Drop,
+    // 照常写入, duplicate 列为 true
+    Flag,
+}
+
+impl DedupMode {
+    /// mode: 0 不去重, 1 丢弃, 2 标记
+    pub fn from_u8(mode: u8) -> Result<Self> {
+        match mode {
+            0 => Ok(DedupMode::Off),
+            1 => Ok(DedupMode::Drop),
+            2 => Ok(DedupMode::Flag),
+            other => Err(Error::InvalidArgument(format!(
+                "dedup mode {} is not support",
+                other
+            ))),
+        }
+    }
+}
+
+pub type DedupKey = (u8, String);
+
+/// 最近写入的 (event_type, trace_id), 最多保留 capacity 个, 超过后淘汰最早的。
+/// 每次写入追加到 dedup.log: `event_type \t trace_id`, 打开时只保留最后 capacity 行,
+/// 文件行数超过 capacity 两倍时重写。不 fsync, 崩溃时可能丢掉最后几条, 只影响去重
+pub struct DedupIndex {
+    path: PathBuf,
+    file: File,
+    capacity: usize,
+    keys: HashSet<DedupKey>,
+    order: VecDeque<DedupKey>,
+    lines: usize,
+}
+
+impl DedupIndex {
+    pub fn open(root: &str, capacity: usize) -> Result<Self> {
+        let path = Path::new(root).join(DEDUP_FILE);
+        let mut index = DedupIndex {
+            file: OpenOptions::new().create(true).append(true).open(&path)?,
+            path,
+            capacity: capacity.max(1),
+            keys: HashSet::new(),
+            order: VecDeque::new(),
+            lines: 0,
+        };
+        let reader = BufReader::new(File::open(&index.path)?);
+        for line in reader.lines() {
+            let line = line?;
+            match parse_line(line.as_str()) {
+                Some(key) => index.remember(key),
+                // 崩溃时最后一行可能不完整
+                None => warn!("dedup {:?} skip bad line {:?}", index.path, line),
+            }
+        }
+        index.compact()?;
+        Ok(index)
+    }
+
+    pub fn contains(&self, event_type: u8, trace_id: &str) -> bool {
+        // HashSet<(u8, String)> 不能直接用 &str 查, 这里拷贝一次
+        self.keys.contains(&(event_type, trace_id.to_string()))
+    }
+
+    /// 写入成功之后调用
+    pub fn insert(&mut self, keys: Vec<DedupKey>) -> Result<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let mut buf = String::new();
+        for key in keys {
+            if self.keys.contains(&key) {
+                continue;
+            }
+            // 带换行的 trace_id 只放在内存里
+            if !key.1.contains('\n') {
+                buf.push_str(format_line(&key).as_str());
+                self.lines += 1;
+            }
+            self.remember(key);
+        }
+        self.file.write_all(buf.as_bytes())?;
+        if self.lines > self.capacity * 2 {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn remember(&mut self, key: DedupKey) {
+        if !self.keys.insert(key.clone()) {
+            return;
+        }
+        self.order.push_back(key);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+    }
+
+    // 只保留内存里的 key 重写文件
+    fn compact(&mut self) -> Result<()> {
+        let tmp = self.path.with_extension("log.tmp");
+        let mut lines = 0;
+        {
+            let mut file = File::create(&tmp)?;
+            let mut buf = String::new();
+            for key in self.order.iter().filter(|k| !k.1.contains('\n')) {
+                buf.push_str(format_line(key).as_str());
+                lines += 1;
+            }
+            file.write_all(buf.as_bytes())?;
+            file.sync_data()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.lines = lines;
+        Ok(())
+    }
+}
+
+fn format_line(key: &DedupKey) -> String {
+    format!("{}\t{}\n", key.0, key.1)
+}
+
+fn parse_line(line: &str) -> Option<DedupKey> {
+    let (event_type, trace_id) = line.split_once('\t')?;
+    Some((event_type.parse().ok()?, trace_id.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evict_oldest_and_reopen() {
+        let root = std::env::temp_dir().join(format!("dedup_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let root = root.to_str().unwrap();
+        {
+            let mut index = DedupIndex::open(root, 3).unwrap();
+            for i in 0..10 {
+                index.insert(vec![(0, format!("t{}", i))]).unwrap();
+            }
+            index.insert(vec![(1, "t9".to_string())]).unwrap();
+            assert!(index.contains(0, "t9"));
+            assert!(index.contains(1, "t9"));
+            assert!(!index.contains(0, "t7"));
+            assert!(index.lines <= 6);
+        }
+
+        let index = DedupIndex::open(root, 3).unwrap();
+        assert!(index.contains(0, "t8"));
+        assert!(index.contains(1, "t9"));
+        assert!(!index.contains(0, "t1"));
+        assert_eq!(index.lines, 3);
+    }
+}
@@ -0,0 +1,110 @@
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::fmt;
+use std::os::raw::c_char;
+
+// 导出函数的返回值, 0 表示成功, 负数表示失败
+pub const OK: i32 = 0;
+pub const ERR_INVALID_ARGUMENT: i32 = -1;
+pub const ERR_IO: i32 = -2;
+pub const ERR_PARQUET: i32 = -3;
+pub const ERR_CLOSED: i32 = -4;
+pub const ERR_PANIC: i32 = -5;
+pub const ERR_INVALID_AMOUNT: i32 = -6;
+pub const ERR_DUPLICATE: i32 = -7;
+pub const ERR_BUSY: i32 = -8;
+pub const ERR_INTERNAL: i32 = -9;
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    InvalidArgument(String),
+    Io(String),
+    Parquet(String),
+    Closed,
+    Panic(String),
+    // amount 不是合法的十进制数, 或者超出了列的精度
+    InvalidAmount(String),
+    // 去重模式为 drop 时, 最近写过相同的 (event_type, trace_id)
+    Duplicate(String),
+    // 队列已满, 稍后重试
+    Busy(String),
+    // 内部状态异常, 例如 group commit 的 leader panic
+    Internal(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn code(&self) -> i32 {
+        match self {
+            Error::InvalidArgument(_) => ERR_INVALID_ARGUMENT,
+            Error::Io(_) => ERR_IO,
+            Error::Parquet(_) => ERR_PARQUET,
+            Error::Closed => ERR_CLOSED,
+            Error::Panic(_) => ERR_PANIC,
+            Error::InvalidAmount(_) => ERR_INVALID_AMOUNT,
+            Error::Duplicate(_) => ERR_DUPLICATE,
+            Error::Busy(_) => ERR_BUSY,
+            Error::Internal(_) => ERR_INTERNAL,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidArgument(msg) => write!(f, "invalid argument: {}", msg),
+            Error::Io(msg) => write!(f, "io error: {}", msg),
+            Error::Parquet(msg) => write!(f, "parquet error: {}", msg),
+            Error::Closed => write!(f, "db is closed"),
+            Error::Panic(msg) => write!(f, "panic: {}", msg),
+            Error::InvalidAmount(msg) => write!(f, "invalid amount: {}", msg),
+            Error::Duplicate(msg) => write!(f, "duplicate event: {}", msg),
+            Error::Busy(msg) => write!(f, "busy: {}", msg),
+            Error::Internal(msg) => write!(f, "internal error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e.to_string())
+    }
+}
+
+impl From<parquet::errors::ParquetError> for Error {
+    fn from(e: parquet::errors::ParquetError) -> Self {
+        Error::Parquet(e.to_string())
+    }
+}
+
+impl From<arrow::error::ArrowError> for Error {
+    fn from(e: arrow::error::ArrowError) -> Self {
+        Error::Parquet(e.to_string())
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+pub fn set_last_error(e: &Error) {
+    // 错误信息里不会有 \0, 万一有就替换掉
+    let msg = e.to_string().replace('\0', " ");
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(msg).ok());
+}
+
+pub fn clear_last_error() {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+}
+
+/// 返回当前线程最近一次失败调用的错误信息, 没有错误时返回 null。
+/// 指针在该线程下一次调用导出函数之前有效。
+pub fn last_error_ptr() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(msg) => msg.as_ptr(),
+        None => std::ptr::null(),
+    })
+}
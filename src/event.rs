@@ -0,0 +1,223 @@
+use std::os::raw::c_char;
+
+use super::db::EventV2;
+use super::error::{Error, Result};
+
+// Event.event_type 的取值
+pub const EVENT_TRADE: u8 = 0;
+pub const EVENT_BALANCE_CHANGE: u8 = 1;
+pub const EVENT_SETTLE_FEE: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Buy = 0,
+    Sell = 1,
+}
+
+impl Side {
+    pub fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Side::Buy),
+            1 => Ok(Side::Sell),
+            other => Err(Error::InvalidArgument(format!(
+                "trade side {} is not support",
+                other
+            ))),
+        }
+    }
+}
+
+/// 成交明细, price 和 qty 是十进制字符串, 写入时转换成 Decimal128
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trade {
+    pub price: String,
+    pub qty: String,
+    pub side: Side,
+}
+
+/// 事件类型以及各自的数据, trace_id 的含义也随类型不同:
+/// trade 是 tradeId, BALANCE-CHANGE 和 SETTLE-FEE 是 eventId
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+    // 旧的 Event 没有成交明细
+    Trade(Option<Trade>),
+    BalanceChange,
+    SettleFee { fee_rate: Option<String> },
+}
+
+impl EventKind {
+    /// 根据 event_type 和可选的明细构造, 不认识的 event_type 返回错误
+    pub fn new(
+        event_type: u8,
+        price: Option<String>,
+        qty: Option<String>,
+        side: u8,
+        fee_rate: Option<String>,
+    ) -> Result<Self> {
+        match event_type {
+            EVENT_TRADE => match (price, qty) {
+                (Some(price), Some(qty)) => Ok(EventKind::Trade(Some(Trade {
+                    price,
+                    qty,
+                    side: Side::from_u8(side)?,
+                }))),
+                (None, None) => Ok(EventKind::Trade(None)),
+                _ => Err(Error::InvalidArgument(
+                    "trade price and qty must be set together".to_string(),
+                )),
+            },
+            EVENT_BALANCE_CHANGE => Ok(EventKind::BalanceChange),
+            EVENT_SETTLE_FEE => Ok(EventKind::SettleFee { fee_rate }),
+            other => Err(Error::InvalidArgument(format!(
+                "event_type {} is not support",
+                other
+            ))),
+        }
+    }
+
+    pub fn event_type(&self) -> u8 {
+        match self {
+            EventKind::Trade(_) => EVENT_TRADE,
+            EventKind::BalanceChange => EVENT_BALANCE_CHANGE,
+            EventKind::SettleFee { .. } => EVENT_SETTLE_FEE,
+        }
+    }
+
+    pub fn trade(&self) -> Option<&Trade> {
+        match self {
+            EventKind::Trade(trade) => trade.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn fee_rate(&self) -> Option<&str> {
+        match self {
+            EventKind::SettleFee { fee_rate } => fee_rate.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+// C 侧的构造函数使用, 没有的字段都是 null / 0
+impl EventV2 {
+    fn base(
+        event_type: u8,
+        account_id: u64,
+        strategy_id: u64,
+        coin: *const c_char,
+        amount: *const c_char,
+        trace_id: *const c_char,
+        event_time_us: i64,
+    ) -> Self {
+        EventV2 {
+            size: std::mem::size_of::<EventV2>() as u32,
+            event_type,
+            account_id,
+            strategy_id,
+            coin,
+            amount,
+            trace_id,
+            fee_coin: std::ptr::null(),
+            fee_amount: std::ptr::null(),
+            event_time_us,
+            price: std::ptr::null(),
+            qty: std::ptr::null(),
+            side: 0,
+            fee_rate: std::ptr::null(),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn trade(
+        account_id: u64,
+        strategy_id: u64,
+        coin: *const c_char,
+        amount: *const c_char,
+        trade_id: *const c_char,
+        price: *const c_char,
+        qty: *const c_char,
+        side: Side,
+        event_time_us: i64,
+    ) -> Self {
+        EventV2 {
+            price,
+            qty,
+            side: side as u8,
+            ..EventV2::base(
+                EVENT_TRADE,
+                account_id,
+                strategy_id,
+                coin,
+                amount,
+                trade_id,
+                event_time_us,
+            )
+        }
+    }
+
+    pub fn balance_change(
+        account_id: u64,
+        strategy_id: u64,
+        coin: *const c_char,
+        amount: *const c_char,
+        event_id: *const c_char,
+        event_time_us: i64,
+    ) -> Self {
+        EventV2::base(
+            EVENT_BALANCE_CHANGE,
+            account_id,
+            strategy_id,
+            coin,
+            amount,
+            event_id,
+            event_time_us,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn settle_fee(
+        account_id: u64,
+        strategy_id: u64,
+        coin: *const c_char,
+        amount: *const c_char,
+        event_id: *const c_char,
+        fee_rate: *const c_char,
+        event_time_us: i64,
+    ) -> Self {
+        EventV2 {
+            fee_rate,
+            ..EventV2::base(
+                EVENT_SETTLE_FEE,
+                account_id,
+                strategy_id,
+                coin,
+                amount,
+                event_id,
+                event_time_us,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_unknown_event_type() {
+        assert!(EventKind::new(3, None, None, 0, None).is_err());
+        assert!(EventKind::new(EVENT_TRADE, Some("1".to_string()), None, 0, None).is_err());
+        assert!(EventKind::new(
+            EVENT_TRADE,
+            Some("1".to_string()),
+            Some("2".to_string()),
+            2,
+            None
+        )
+        .is_err());
+        assert_eq!(
+            EventKind::new(EVENT_BALANCE_CHANGE, None, None, 0, None).unwrap(),
+            EventKind::BalanceChange
+        );
+    }
+}
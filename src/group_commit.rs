@@ -0,0 +1,622 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use super::error::{Error, Result};
+
+use sync::{AtomicU64, Condvar, Mutex, Ordering};
+
+// loom 只能检查它自己的同步原语, 其他时候和别的模块一样用 parking_lot。
+// 两边的 api 不一样, 用这几个函数统一成按值传递 guard
+#[cfg(group_commit_loom)]
+mod sync {
+    pub use loom::sync::atomic::{AtomicU64, Ordering};
+    pub use loom::sync::{Condvar, Mutex, MutexGuard};
+    use std::time::Instant;
+
+    pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+        mutex.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn wait<'a, T>(cond: &Condvar, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        cond.wait(guard).unwrap()
+    }
+
+    pub fn wait_until<'a, T>(
+        cond: &Condvar,
+        guard: MutexGuard<'a, T>,
+        deadline: Instant,
+    ) -> MutexGuard<'a, T> {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        cond.wait_timeout(guard, timeout).unwrap().0
+    }
+}
+
+#[cfg(not(group_commit_loom))]
+mod sync {
+    pub use parking_lot::{Condvar, Mutex, MutexGuard};
+    pub use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Instant;
+
+    pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+        mutex.lock()
+    }
+
+    pub fn wait<'a, T>(cond: &Condvar, mut guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        cond.wait(&mut guard);
+        guard
+    }
+
+    pub fn wait_until<'a, T>(
+        cond: &Condvar,
+        mut guard: MutexGuard<'a, T>,
+        deadline: Instant,
+    ) -> MutexGuard<'a, T> {
+        cond.wait_until(&mut guard, deadline);
+        guard
+    }
+}
+
+/// 队列满了之后新的请求怎么处理
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    // 一直等到队列有空间
+    Block,
+    // 最多等这么久, 超时返回 Error::Busy
+    Timeout(Duration),
+    // 直接返回 Error::Busy
+    Reject,
+}
+
+/// 排队的行数和字节数, 以及每一批的行数和字节数上限, None 表示不限制。
+/// 单个请求超过上限时仍然会被接受, 并且单独成为一批
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    pub max_queue_rows: Option<usize>,
+    pub max_queue_bytes: Option<usize>,
+    pub max_batch_rows: Option<usize>,
+    pub max_batch_bytes: Option<usize>,
+    pub backpressure: Backpressure,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_queue_rows: Some(100_000),
+            max_queue_bytes: Some(64 * 1024 * 1024),
+            max_batch_rows: Some(10_000),
+            max_batch_bytes: Some(16 * 1024 * 1024),
+            backpressure: Backpressure::Block,
+        }
+    }
+}
+
+/// 请求的大小, 用来计算排队的行数和每一批的大小
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Size {
+    pub rows: usize,
+    pub bytes: usize,
+}
+
+struct State<T, R> {
+    // 等待 leader 取走的请求
+    pending: VecDeque<(u64, Size, T)>,
+    pending_rows: usize,
+    pending_bytes: usize,
+    // leader 写完之后放在这里, 由各自的调用方取走
+    done: BTreeMap<u64, R>,
+    // leader 在 commit 里 panic 时, 这一批其他调用方的 ticket
+    abandoned: HashSet<u64>,
+    next_ticket: u64,
+    // 同一时间只有一个 leader
+    leader: bool,
+}
+
+/// group commit: 调用方把请求放进队列, 没有 leader 时队列头部的调用方成为 leader,
+/// 按 limits 从队列头部取一批请求交给 commit, 其他调用方等待各自的结果。
+/// 所有状态都在 Mutex 里, 请求和结果都是按值移动, 不需要裸指针
+pub struct GroupCommit<T, R> {
+    limits: Limits,
+    state: Mutex<State<T, R>>,
+    // 等待结果和等待成为 leader 的调用方
+    cond: Condvar,
+    // 等待队列有空间的调用方, 和 cond 分开, leader 取走一批时不会唤醒等待结果的调用方
+    space: Condvar,
+    // 触发背压的次数
+    throttled: AtomicU64,
+}
+
+impl<T, R> Default for GroupCommit<T, R> {
+    fn default() -> Self {
+        GroupCommit::new(Limits::default())
+    }
+}
+
+impl<T, R> GroupCommit<T, R> {
+    pub fn new(limits: Limits) -> Self {
+        GroupCommit {
+            limits,
+            state: Mutex::new(State {
+                pending: VecDeque::new(),
+                pending_rows: 0,
+                pending_bytes: 0,
+                done: BTreeMap::new(),
+                abandoned: HashSet::new(),
+                next_ticket: 0,
+                leader: false,
+            }),
+            cond: Condvar::new(),
+            space: Condvar::new(),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    /// 触发背压 (排队的行数达到上限) 的次数
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    /// commit 不持有锁执行, 必须按顺序为每个请求返回一个结果,
+    /// 同一时间只有一个 commit 在执行。自己的请求不在这一批时 leader 会继续处理下一批
+    pub fn submit<F>(&self, item: T, size: Size, commit: F) -> Result<R>
+    where
+        F: FnMut(Vec<T>) -> Vec<R>,
+    {
+        self.submit_with(item, size, self.limits.backpressure, commit)
+    }
+
+    /// 和 submit 一样, 但是队列满时按 backpressure 处理, 而不是 limits 里配置的策略
+    pub fn submit_with<F>(
+        &self,
+        item: T,
+        size: Size,
+        backpressure: Backpressure,
+        mut commit: F,
+    ) -> Result<R>
+    where
+        F: FnMut(Vec<T>) -> Vec<R>,
+    {
+        let mut state = sync::lock(&self.state);
+        if self.limits.max_queue_rows.is_some() || self.limits.max_queue_bytes.is_some() {
+            let over = |value: usize, max: Option<usize>| matches!(max, Some(max) if value > max);
+            // 队列为空时总是接受, 否则超过上限的单个请求永远进不来
+            let full = |state: &State<T, R>| {
+                state.pending_rows > 0
+                    && (over(state.pending_rows + size.rows, self.limits.max_queue_rows)
+                        || over(
+                            state.pending_bytes + size.bytes,
+                            self.limits.max_queue_bytes,
+                        ))
+            };
+            if full(&state) {
+                self.throttled.fetch_add(1, Ordering::Relaxed);
+                let deadline = match backpressure {
+                    Backpressure::Reject => return Err(busy()),
+                    Backpressure::Block => None,
+                    Backpressure::Timeout(timeout) => Some(Instant::now() + timeout),
+                };
+                while full(&state) {
+                    state = match deadline {
+                        None => sync::wait(&self.space, state),
+                        Some(deadline) => {
+                            if Instant::now() >= deadline {
+                                return Err(busy());
+                            }
+                            sync::wait_until(&self.space, state, deadline)
+                        }
+                    };
+                }
+            }
+        }
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.pending.push_back((ticket, size, item));
+        state.pending_rows += size.rows;
+        state.pending_bytes += size.bytes;
+        loop {
+            if let Some(result) = state.done.remove(&ticket) {
+                return Ok(result);
+            }
+            if !state.abandoned.is_empty() && state.abandoned.remove(&ticket) {
+                return Err(Error::Internal("group commit leader panicked".to_string()));
+            }
+            // 只有队列头部的调用方成为 leader, 刚写完一批的 leader 再次提交时要排在后面,
+            // 否则它会马上带着很小的一批再做一次 commit
+            let front = state.pending.front().map(|(t, _, _)| *t);
+            if state.leader || front != Some(ticket) {
+                state = sync::wait(&self.cond, state);
+                continue;
+            }
+
+            // 成为 leader
+            state.leader = true;
+            let (tickets, items) = self.take_batch(&mut state);
+            // 队列有空间了, 唤醒等待入队的调用方
+            self.space.notify_all();
+            drop(state);
+
+            let guard = LeaderGuard {
+                gc: self,
+                own: ticket,
+                tickets: tickets.as_slice(),
+            };
+            let results = commit(items);
+            assert_eq!(results.len(), tickets.len(), "one result per item");
+            drop(guard);
+
+            state = sync::lock(&self.state);
+            state.done.extend(tickets.into_iter().zip(results));
+            state.leader = false;
+            // 唤醒等待结果的调用方, 以及下一个 leader
+            self.cond.notify_all();
+        }
+    }
+
+    // 从队列头部取一批, 至少一个请求
+    fn take_batch(&self, state: &mut State<T, R>) -> (Vec<u64>, Vec<T>) {
+        let (mut rows, mut bytes) = (0, 0);
+        let mut tickets = Vec::with_capacity(state.pending.len());
+        let mut items = Vec::with_capacity(state.pending.len());
+        let over = |value: usize, max: Option<usize>| matches!(max, Some(max) if value > max);
+        while let Some((_, size, _)) = state.pending.front() {
+            if !items.is_empty()
+                && (over(rows + size.rows, self.limits.max_batch_rows)
+                    || over(bytes + size.bytes, self.limits.max_batch_bytes))
+            {
+                break;
+            }
+            let (ticket, size, item) = state.pending.pop_front().unwrap();
+            rows += size.rows;
+            bytes += size.bytes;
+            state.pending_rows -= size.rows;
+            state.pending_bytes -= size.bytes;
+            tickets.push(ticket);
+            items.push(item);
+        }
+        (tickets, items)
+    }
+}
+
+fn busy() -> Error {
+    Error::Busy("write queue is full".to_string())
+}
+
+// commit panic 时释放 leader, 并让这一批的其他调用方返回 Error::Internal, 而不是一直等待
+struct LeaderGuard<'a, T, R> {
+    gc: &'a GroupCommit<T, R>,
+    own: u64,
+    tickets: &'a [u64],
+}
+
+impl<T, R> Drop for LeaderGuard<'_, T, R> {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            return;
+        }
+        let mut state = sync::lock(&self.gc.state);
+        let own = self.own;
+        state
+            .abandoned
+            .extend(self.tickets.iter().copied().filter(|t| *t != own));
+        // 自己的请求还没轮到的话也不再写
+        if let Some(i) = state.pending.iter().position(|(t, _, _)| *t == own) {
+            let (_, size, _) = state.pending.remove(i).unwrap();
+            state.pending_rows -= size.rows;
+            state.pending_bytes -= size.bytes;
+        }
+        state.leader = false;
+        self.gc.cond.notify_all();
+        self.gc.space.notify_all();
+    }
+}
+
+#[cfg(all(test, not(group_commit_loom)))]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    const ONE: Size = Size { rows: 1, bytes: 8 };
+
+    // 也可以用 cargo miri test group_commit 检查
+    #[test]
+    fn concurrent_submit_gets_own_result() {
+        let gc = Arc::new(GroupCommit::<u64, u64>::default());
+        let handles = (0..4u64)
+            .map(|t| {
+                let gc = gc.clone();
+                std::thread::spawn(move || {
+                    for i in 0..50 {
+                        let item = t * 1000 + i;
+                        let result = gc
+                            .submit(item, ONE, |items| items.iter().map(|v| v * 2).collect())
+                            .unwrap();
+                        assert_eq!(result, item * 2);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    // leader 卡在 commit 里, 另一个请求在排队, 返回放行 leader 的 sender
+    fn blocked_leader(
+        gc: &Arc<GroupCommit<u64, u64>>,
+    ) -> (
+        std::sync::mpsc::Sender<()>,
+        std::thread::JoinHandle<u64>,
+        std::thread::JoinHandle<u64>,
+    ) {
+        let (entered, entered_recv) = std::sync::mpsc::channel();
+        let (release, release_recv) = std::sync::mpsc::channel::<()>();
+        let leader = {
+            let gc = gc.clone();
+            std::thread::spawn(move || {
+                gc.submit(1, ONE, |items| {
+                    entered.send(()).unwrap();
+                    release_recv.recv().unwrap();
+                    items
+                })
+                .unwrap()
+            })
+        };
+        entered_recv.recv().unwrap();
+        let follower = {
+            let gc = gc.clone();
+            std::thread::spawn(move || gc.submit(2, ONE, |items| items).unwrap())
+        };
+        while sync::lock(&gc.state).pending_rows == 0 {
+            std::thread::yield_now();
+        }
+        (release, leader, follower)
+    }
+
+    #[test]
+    fn reject_when_queue_is_full() {
+        let gc = Arc::new(GroupCommit::<u64, u64>::new(Limits {
+            max_queue_rows: Some(1),
+            max_batch_rows: Some(1),
+            backpressure: Backpressure::Reject,
+            ..Limits::default()
+        }));
+        let (release, leader, follower) = blocked_leader(&gc);
+        assert!(gc.submit(3, ONE, |items| items).is_err());
+        assert_eq!(gc.throttled(), 1);
+
+        release.send(()).unwrap();
+        assert_eq!(leader.join().unwrap(), 1);
+        assert_eq!(follower.join().unwrap(), 2);
+        assert_eq!(gc.submit(4, ONE, |items| items).unwrap(), 4);
+    }
+
+    #[test]
+    fn timeout_when_queue_bytes_are_full() {
+        let timeout = Duration::from_millis(200);
+        let gc = Arc::new(GroupCommit::<u64, u64>::new(Limits {
+            max_queue_rows: None,
+            max_queue_bytes: Some(ONE.bytes),
+            max_batch_rows: Some(1),
+            backpressure: Backpressure::Timeout(timeout),
+            ..Limits::default()
+        }));
+        let (release, leader, follower) = blocked_leader(&gc);
+        let start = Instant::now();
+        assert!(gc.submit(3, ONE, |items| items).is_err());
+        assert!(start.elapsed() >= timeout);
+        assert_eq!(gc.throttled(), 1);
+
+        // 等待期间队列有了空间就不会超时
+        let waiter = {
+            let gc = gc.clone();
+            std::thread::spawn(move || gc.submit(4, ONE, |items| items).unwrap())
+        };
+        while gc.throttled() < 2 {
+            std::thread::yield_now();
+        }
+        release.send(()).unwrap();
+        assert_eq!(leader.join().unwrap(), 1);
+        assert_eq!(follower.join().unwrap(), 2);
+        assert_eq!(waiter.join().unwrap(), 4);
+    }
+
+    #[test]
+    fn abandoned_follower_gets_error() {
+        let gc = Arc::new(GroupCommit::<u64, u64>::default());
+        // 第一个 leader 卡住, 后面两个请求会成为同一批
+        let (entered, entered_recv) = std::sync::mpsc::channel();
+        let (release, release_recv) = std::sync::mpsc::channel::<()>();
+        let leader = {
+            let gc = gc.clone();
+            std::thread::spawn(move || {
+                gc.submit(1, ONE, |items| {
+                    entered.send(()).unwrap();
+                    release_recv.recv().unwrap();
+                    items
+                })
+                .unwrap()
+            })
+        };
+        entered_recv.recv().unwrap();
+        let submit = |item| {
+            let gc = gc.clone();
+            std::thread::spawn(move || {
+                gc.submit(item, ONE, |items| {
+                    assert_eq!(items.len(), 1, "boom");
+                    items
+                })
+            })
+        };
+        let first = submit(2);
+        while sync::lock(&gc.state).pending_rows < 1 {
+            std::thread::yield_now();
+        }
+        let second = submit(3);
+        while sync::lock(&gc.state).pending_rows < 2 {
+            std::thread::yield_now();
+        }
+        release.send(()).unwrap();
+        assert_eq!(leader.join().unwrap(), 1);
+
+        // 排在前面的请求成为 leader 并 panic, 另一个拿到错误
+        let results = [first.join(), second.join()];
+        assert_eq!(results.iter().filter(|r| r.is_err()).count(), 1);
+        assert!(results
+            .iter()
+            .any(|r| matches!(r, Ok(Err(Error::Internal(_))))));
+        assert_eq!(gc.submit(4, ONE, |items| items).unwrap(), 4);
+    }
+
+    // 替换之前的实现: 调用方把栈上 Waiter 的裸指针放进队列, 队头的调用方成为 leader,
+    // 一次取走整个队列, 写完之后逐个标记 done。只用来对比吞吐
+    struct LegacyGroup {
+        queue: parking_lot::Mutex<VecDeque<*const LegacyWaiter>>,
+        cond: parking_lot::Condvar,
+    }
+
+    struct LegacyWaiter {
+        done: std::cell::Cell<bool>,
+    }
+
+    unsafe impl Send for LegacyGroup {}
+    unsafe impl Sync for LegacyGroup {}
+
+    impl LegacyGroup {
+        fn submit<F: FnMut(usize)>(&self, mut commit: F) {
+            let waiter = LegacyWaiter {
+                done: std::cell::Cell::new(false),
+            };
+            let own = &waiter as *const LegacyWaiter;
+            let mut queue = self.queue.lock();
+            queue.push_back(own);
+            while !waiter.done.get() && *queue.front().unwrap() != own {
+                self.cond.wait(&mut queue);
+            }
+            if waiter.done.get() {
+                return;
+            }
+            let last = *queue.back().unwrap();
+            let n = queue.len();
+            drop(queue);
+
+            commit(n);
+
+            let mut queue = self.queue.lock();
+            while let Some(front) = queue.pop_front() {
+                if front != own {
+                    unsafe { (*front).done.set(true) };
+                }
+                if front == last {
+                    break;
+                }
+            }
+            self.cond.notify_all();
+        }
+    }
+
+    // 每个线程写 per_thread 次, 返回耗时和 commit 的次数
+    fn run_throughput<S>(threads: usize, per_thread: usize, submit: S) -> (Duration, u64)
+    where
+        S: Fn(&dyn Fn(usize)) + Send + Sync + 'static,
+    {
+        let submit = Arc::new(submit);
+        let batches = Arc::new(AtomicU64::new(0));
+        let start = Instant::now();
+        let handles = (0..threads)
+            .map(|_| {
+                let submit = submit.clone();
+                let batches = batches.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..per_thread {
+                        submit(&|_| {
+                            batches.fetch_add(1, Ordering::Relaxed);
+                            // 模拟 wal fsync 的开销
+                            std::thread::sleep(Duration::from_micros(50));
+                        });
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        (start.elapsed(), batches.load(Ordering::Relaxed))
+    }
+
+    // 和替换之前的实现比较吞吐, 不能明显变慢
+    #[test]
+    fn group_commit_throughput() {
+        let threads = 16;
+        let per_thread = 2_000;
+        let throughput =
+            |(elapsed, _): (Duration, u64)| (threads * per_thread) as f64 / elapsed.as_secs_f64();
+
+        let legacy = Arc::new(LegacyGroup {
+            queue: parking_lot::Mutex::new(VecDeque::new()),
+            cond: parking_lot::Condvar::new(),
+        });
+        let gc = Arc::new(GroupCommit::<usize, ()>::default());
+        // 交替跑几轮, 各自取最好的一次, 减少机器负载波动的影响
+        let (mut before, mut after) = (0f64, 0f64);
+        for _ in 0..3 {
+            let legacy = legacy.clone();
+            before = before.max(throughput(run_throughput(
+                threads,
+                per_thread,
+                move |commit| legacy.submit(commit),
+            )));
+            let gc = gc.clone();
+            after = after.max(throughput(run_throughput(
+                threads,
+                per_thread,
+                move |commit| {
+                    gc.submit(0, ONE, |items| {
+                        commit(items.len());
+                        vec![(); items.len()]
+                    })
+                    .unwrap()
+                },
+            )));
+        }
+        assert!(
+            after / before >= 0.9,
+            "group_commit {:.0} writes/s, legacy {:.0} writes/s",
+            after,
+            before
+        );
+    }
+}
+
+// RUSTFLAGS="--cfg group_commit_loom" cargo test --release --lib group_commit
+#[cfg(all(test, group_commit_loom))]
+mod tests {
+    use super::*;
+    use loom::sync::Arc;
+
+    const ONE: Size = Size { rows: 1, bytes: 8 };
+
+    #[test]
+    fn handoff_between_leader_and_follower() {
+        loom::model(|| {
+            let gc = Arc::new(GroupCommit::<u32, u32>::default());
+            let handles = (0..2u32)
+                .map(|t| {
+                    let gc = gc.clone();
+                    loom::thread::spawn(move || {
+                        let result = gc
+                            .submit(t, ONE, |items| items.iter().map(|v| v + 10).collect())
+                            .unwrap();
+                        assert_eq!(result, t + 10);
+                    })
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                gc.submit(7, ONE, |items| items.iter().map(|v| v + 10).collect())
+                    .unwrap(),
+                17
+            );
+            for handle in handles {
+                handle.join().unwrap();
+            }
+        });
+    }
+}
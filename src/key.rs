@@ -0,0 +1,202 @@
+use super::error::{Error, Result};
+
+/// 沿用之前的目录结构, 文件名带上 host, 多台机器写同一个 bucket 时不会互相覆盖:
+/// experience_coupon/20230316/17/host-a-1.parquet
+pub const DEFAULT_KEY_TEMPLATE: &str = "{prefix}/{date}/{hour}/{host}-{seq}.parquet";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Var {
+    Prefix,
+    // 20230316
+    Date,
+    // 2023-03-16
+    Dt,
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Host,
+    Seq,
+    // 本地文件名, 例如 20230316_17_1.parquet
+    File,
+}
+
+impl Var {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "prefix" => Var::Prefix,
+            "date" => Var::Date,
+            "dt" => Var::Dt,
+            "year" => Var::Year,
+            "month" => Var::Month,
+            "day" => Var::Day,
+            "hour" => Var::Hour,
+            "minute" => Var::Minute,
+            "host" => Var::Host,
+            "seq" => Var::Seq,
+            "file" => Var::File,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Var(Var),
+}
+
+/// s3 key 模板, 例如 `{prefix}/dt={dt}/hour={hour}/{host}-{seq}.parquet`,
+/// 变量都从本地文件名 `YYYYMMDD_HH[MM]_N.parquet` 里解析出来
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyTemplate {
+    segments: Vec<Segment>,
+}
+
+impl KeyTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}').ok_or_else(|| {
+                Error::InvalidArgument(format!("key template {} has unclosed '{{'", template))
+            })? + start;
+            let name = &rest[start + 1..end];
+            let var = Var::parse(name).ok_or_else(|| {
+                Error::InvalidArgument(format!("key template variable {{{}}} is not support", name))
+            })?;
+            segments.push(Segment::Var(var));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+        Ok(KeyTemplate { segments })
+    }
+
+    pub fn has(&self, var: Var) -> bool {
+        self.segments.contains(&Segment::Var(var))
+    }
+
+    pub fn render(&self, prefix: &str, host: &str, file_name: &str) -> anyhow::Result<String> {
+        let parts = FileParts::parse(file_name)
+            .ok_or_else(|| anyhow::anyhow!("file_name {} is not support", file_name))?;
+        let dt = format!(
+            "{}-{}-{}",
+            &parts.date[..4],
+            &parts.date[4..6],
+            &parts.date[6..8]
+        );
+        let mut key = String::new();
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Literal(value) => key.push_str(value.as_str()),
+                Segment::Var(var) => key.push_str(match var {
+                    Var::Prefix => prefix,
+                    Var::Date => parts.date,
+                    Var::Dt => dt.as_str(),
+                    Var::Year => &parts.date[..4],
+                    Var::Month => &parts.date[4..6],
+                    Var::Day => &parts.date[6..8],
+                    Var::Hour => parts.hour,
+                    Var::Minute => parts.minute,
+                    Var::Host => host,
+                    Var::Seq => parts.seq,
+                    Var::File => file_name,
+                }),
+            }
+        }
+        // prefix 为空时去掉多余的 /
+        while key.contains("//") {
+            key = key.replace("//", "/");
+        }
+        Ok(key.trim_start_matches('/').to_string())
+    }
+}
+
+struct FileParts<'a> {
+    date: &'a str,
+    hour: &'a str,
+    minute: &'a str,
+    seq: &'a str,
+}
+
+impl<'a> FileParts<'a> {
+    // 20230316_17_1.parquet 或者 20230316_1705_1.parquet
+    fn parse(file_name: &'a str) -> Option<Self> {
+        let stem = file_name.strip_suffix(".parquet")?;
+        let names = stem.split('_').collect::<Vec<_>>();
+        if names.len() != 3 || !names.iter().all(|n| is_digits(n)) || names[0].len() != 8 {
+            return None;
+        }
+        let (hour, minute) = match names[1].len() {
+            2 => (names[1], "00"),
+            4 => (&names[1][..2], &names[1][2..]),
+            _ => return None,
+        };
+        Some(FileParts {
+            date: names[0],
+            hour,
+            minute,
+            seq: names[2],
+        })
+    }
+}
+
+fn is_digits(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit())
+}
+
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return "unknown".to_string();
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_template_keeps_old_layout() {
+        let template = KeyTemplate::parse(DEFAULT_KEY_TEMPLATE).unwrap();
+        let key = template
+            .render("experience_coupon", "host-a", "20230316_17_1.parquet")
+            .unwrap();
+        assert_eq!(key, "experience_coupon/20230316/17/host-a-1.parquet");
+    }
+
+    #[test]
+    fn hive_template() {
+        let template =
+            KeyTemplate::parse("{prefix}/dt={dt}/hour={hour}/{host}-{seq}.parquet").unwrap();
+        let key = template
+            .render("experience_coupon", "host-a", "20230316_1705_2.parquet")
+            .unwrap();
+        assert_eq!(
+            key,
+            "experience_coupon/dt=2023-03-16/hour=17/host-a-2.parquet"
+        );
+        let key = template
+            .render("", "host-a", "20230316_17_2.parquet")
+            .unwrap();
+        assert_eq!(key, "dt=2023-03-16/hour=17/host-a-2.parquet");
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(KeyTemplate::parse("{prefix}/{unknown}").is_err());
+        assert!(KeyTemplate::parse("{prefix/{seq}").is_err());
+        let template = KeyTemplate::parse(DEFAULT_KEY_TEMPLATE).unwrap();
+        assert!(template.render("p", "h", "20230316_17.parquet").is_err());
+        assert!(template.render("p", "h", "wal.log").is_err());
+    }
+}
@@ -1,37 +1,408 @@
+// C ABI 的指针参数由调用方保证有效, 导出函数不标 unsafe
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
 use std::{os::raw::c_char, sync::Arc};
+mod async_write;
+mod config;
 mod db;
+mod decimal;
+mod dedup;
+mod error;
+mod event;
+mod group_commit;
+mod key;
+mod manifest;
+mod metrics;
+mod props;
+mod reader;
+mod rotation;
 mod s3;
+mod schema;
+mod store;
+mod wal;
+mod zone;
 
+use config::{DbConfig, Options};
 use db::*;
-use std::ffi::{CStr, CString};
+use error::{clear_last_error, last_error_ptr, set_last_error, Error, Result, OK};
+use metrics::MetricsSnapshot;
+use reader::{EventFilter, Filter, QueryCallback};
+use std::ffi::CStr;
+use std::panic::{self, AssertUnwindSafe};
 
 #[repr(C)]
 pub struct Writer {
     _db: *mut Arc<db::Db>,
 }
 
+// 导出函数统一走这里: 把错误转换成返回码, 并且不让 panic 穿过 FFI 边界
+fn ffi_call<F: FnOnce() -> Result<()>>(f: F) -> i32 {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => {
+            clear_last_error();
+            OK
+        }
+        Ok(Err(e)) => {
+            set_last_error(&e);
+            e.code()
+        }
+        Err(payload) => {
+            let msg = if let Some(msg) = payload.downcast_ref::<&str>() {
+                msg.to_string()
+            } else if let Some(msg) = payload.downcast_ref::<String>() {
+                msg.clone()
+            } else {
+                "unknown panic".to_string()
+            };
+            let e = Error::Panic(msg);
+            set_last_error(&e);
+            e.code()
+        }
+    }
+}
+
+fn get_db<'a>(w: *mut Writer) -> Result<&'a Arc<db::Db>> {
+    if w.is_null() {
+        return Err(Error::InvalidArgument("writer is null".to_string()));
+    }
+    let ptr = unsafe { (*w)._db };
+    if ptr.is_null() {
+        return Err(Error::InvalidArgument("db is not initialized".to_string()));
+    }
+    Ok(unsafe { &*ptr })
+}
+
+#[no_mangle]
+pub extern "C" fn write_db(w: *mut Writer, event: *const Event) -> i32 {
+    ffi_call(|| {
+        let _db = get_db(w)?;
+        if event.is_null() {
+            return Err(Error::InvalidArgument("event is null".to_string()));
+        }
+        _db.write(event)
+    })
+}
+
+/// 一组事件在同一轮 group commit 里写入, accepted 写入成功的数量,
+/// first_failed 写入第一个失败事件的下标 (全部成功时为 count), 返回值是第一个失败的错误码
 #[no_mangle]
-pub extern "C" fn write_db(w: *mut Writer, event: *const Event) {
-    let _db = unsafe { &*(*w)._db.clone() };
-    _db.write(event);
+pub extern "C" fn write_db_batch(
+    w: *mut Writer,
+    events: *const Event,
+    count: usize,
+    accepted: *mut usize,
+    first_failed: *mut usize,
+) -> i32 {
+    ffi_call(|| {
+        let _db = get_db(w)?;
+        if events.is_null() && count != 0 {
+            return Err(Error::InvalidArgument("events is null".to_string()));
+        }
+        let events: &[Event] = if count == 0 {
+            &[]
+        } else {
+            unsafe { std::slice::from_raw_parts(events, count) }
+        };
+        let results = _db.write_batch(events);
+        let ok = results.iter().filter(|ret| ret.is_ok()).count();
+        let first = results.iter().position(|ret| ret.is_err());
+        if !accepted.is_null() {
+            unsafe { accepted.write(ok) };
+        }
+        if !first_failed.is_null() {
+            unsafe { first_failed.write(first.unwrap_or(count)) };
+        }
+        match first {
+            Some(i) => results.into_iter().nth(i).unwrap(),
+            None => Ok(()),
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn write_db_v2(w: *mut Writer, event: *const EventV2) -> i32 {
+    ffi_call(|| {
+        let _db = get_db(w)?;
+        if event.is_null() {
+            return Err(Error::InvalidArgument("event is null".to_string()));
+        }
+        _db.write_v2(event)
+    })
+}
+
+/// 拷贝事件入队后立即返回, seq 写入这条事件的序号 (可以为 NULL)。
+/// 写完之后在后台线程调用 callback (可以为 NULL), 也可以用 async_status 轮询
+#[no_mangle]
+pub extern "C" fn write_db_async(
+    w: *mut Writer,
+    event: *const EventV2,
+    callback: Option<async_write::WriteCallback>,
+    ctx: *mut libc::c_void,
+    seq: *mut u64,
+) -> i32 {
+    ffi_call(|| {
+        let _db = get_db(w)?;
+        if event.is_null() {
+            return Err(Error::InvalidArgument("event is null".to_string()));
+        }
+        let record = Record::from_event_v2(unsafe { &*event })?;
+        let value = _db.write_async(record, callback.map(|f| (f, ctx)))?;
+        if !seq.is_null() {
+            unsafe { seq.write(value) };
+        }
+        Ok(())
+    })
 }
 
+/// persisted_seq 写入已经写入的最大序号, failed 写入失败的数量, failed_seq 写入最近一条失败的序号,
+/// 小于等于 persisted_seq 并且大于 failed_seq 的事件都已经写入
 #[no_mangle]
-pub extern "C" fn init_db(w: *mut Writer) {
-    env_logger::init();
-    let _db = Box::new(Arc::new(db::Db::init(
-        "/Users/zuoxiaoliang/project/rust/experence_persist/db",
-    )));
+pub extern "C" fn async_status(
+    w: *mut Writer,
+    persisted_seq: *mut u64,
+    failed: *mut u64,
+    failed_seq: *mut u64,
+) -> i32 {
+    ffi_call(|| {
+        let (seq, count, last_failed) = get_db(w)?.async_status();
+        if !persisted_seq.is_null() {
+            unsafe { persisted_seq.write(seq) };
+        }
+        if !failed.is_null() {
+            unsafe { failed.write(count) };
+        }
+        if !failed_seq.is_null() {
+            unsafe { failed_seq.write(last_failed) };
+        }
+        Ok(())
+    })
+}
+
+// 构造函数把 out 填成对应类型的 EventV2, 字符串指针只是保存下来, 调用 write_db_v2 之前要保持有效
+fn fill_event(out: *mut EventV2, event: EventV2) -> Result<()> {
+    if out.is_null() {
+        return Err(Error::InvalidArgument("event is null".to_string()));
+    }
+    unsafe { out.write(event) };
+    Ok(())
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub extern "C" fn event_trade(
+    out: *mut EventV2,
+    account_id: u64,
+    strategy_id: u64,
+    coin: *const c_char,
+    amount: *const c_char,
+    trade_id: *const c_char,
+    price: *const c_char,
+    qty: *const c_char,
+    side: u8,
+    event_time_us: i64,
+) -> i32 {
+    ffi_call(|| {
+        let event = EventV2::trade(
+            account_id,
+            strategy_id,
+            coin,
+            amount,
+            trade_id,
+            price,
+            qty,
+            event::Side::from_u8(side)?,
+            event_time_us,
+        );
+        fill_event(out, event)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn event_balance_change(
+    out: *mut EventV2,
+    account_id: u64,
+    strategy_id: u64,
+    coin: *const c_char,
+    amount: *const c_char,
+    event_id: *const c_char,
+    event_time_us: i64,
+) -> i32 {
+    ffi_call(|| {
+        let event = EventV2::balance_change(
+            account_id,
+            strategy_id,
+            coin,
+            amount,
+            event_id,
+            event_time_us,
+        );
+        fill_event(out, event)
+    })
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub extern "C" fn event_settle_fee(
+    out: *mut EventV2,
+    account_id: u64,
+    strategy_id: u64,
+    coin: *const c_char,
+    amount: *const c_char,
+    event_id: *const c_char,
+    fee_rate: *const c_char,
+    event_time_us: i64,
+) -> i32 {
+    ffi_call(|| {
+        let event = EventV2::settle_fee(
+            account_id,
+            strategy_id,
+            coin,
+            amount,
+            event_id,
+            fee_rate,
+            event_time_us,
+        );
+        fill_event(out, event)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn init_db(w: *mut Writer) -> i32 {
+    ffi_call(|| open_db(w, Options::default()))
+}
+
+#[no_mangle]
+pub extern "C" fn init_db_with_config(w: *mut Writer, config: *const DbConfig) -> i32 {
+    ffi_call(|| {
+        let options = Options::from_c(&DbConfig::from_ptr(config)?)?;
+        open_db(w, options)
+    })
+}
+
+fn open_db(w: *mut Writer, options: Options) -> Result<()> {
+    if w.is_null() {
+        return Err(Error::InvalidArgument("writer is null".to_string()));
+    }
+    let _ = env_logger::try_init();
+    let metrics_addr = options.metrics_addr.clone();
+    let _db = Box::new(Arc::new(db::Db::init(options)?));
+    if let Some(addr) = metrics_addr {
+        if let Err(e) = _db.serve_metrics(addr.as_str()) {
+            let _ = _db.close_db();
+            return Err(e);
+        }
+    }
     let ptr = Box::into_raw(_db);
     unsafe {
         (*w)._db = ptr;
     }
+    Ok(())
+}
+
+#[no_mangle]
+pub extern "C" fn close_db(w: *mut Writer) -> i32 {
+    ffi_call(|| get_db(w)?.close_db())
+}
+
+/// 按条件查询本地已经写完的文件, 每一行调用一次 callback,
+/// callback 返回非 0 时停止。filter 为 NULL 时返回全部
+#[no_mangle]
+pub extern "C" fn query_db(
+    w: *mut Writer,
+    filter: *const EventFilter,
+    callback: Option<QueryCallback>,
+    ctx: *mut libc::c_void,
+) -> i32 {
+    ffi_call(|| {
+        let _db = get_db(w)?;
+        let callback =
+            callback.ok_or_else(|| Error::InvalidArgument("callback is null".to_string()))?;
+        let filter = if filter.is_null() {
+            Filter::default()
+        } else {
+            Filter::from_c(unsafe { &*filter })?
+        };
+        _db.query(&filter, |row| row.with_c(|row| callback(row, ctx) == 0))
+    })
+}
+
+/// 查询 trace_id 是否写入过, exists 写入 1 或 0。event_type 为负数时不区分类型
+#[no_mangle]
+pub extern "C" fn exists_trace_id(
+    w: *mut Writer,
+    event_type: i32,
+    trace_id: *const c_char,
+    exists: *mut u8,
+) -> i32 {
+    ffi_call(|| {
+        let _db = get_db(w)?;
+        if trace_id.is_null() || exists.is_null() {
+            return Err(Error::InvalidArgument(
+                "trace_id and exists must not be null".to_string(),
+            ));
+        }
+        let trace_id = unsafe { CStr::from_ptr(trace_id) }
+            .to_str()
+            .map_err(|e| Error::InvalidArgument(format!("trace_id is not valid utf-8: {}", e)))?;
+        let event_type =
+            match event_type {
+                t if t < 0 => None,
+                t => Some(u8::try_from(t).map_err(|_| {
+                    Error::InvalidArgument(format!("event_type {} is not support", t))
+                })?),
+            };
+        let found = _db.exists_trace_id(event_type, trace_id)?;
+        unsafe { exists.write(found as u8) };
+        Ok(())
+    })
+}
+
+/// count 写入触发背压的次数: write_db 排队达到上限, 以及 write_db_async 队列已满
+#[no_mangle]
+pub extern "C" fn backpressure_count(w: *mut Writer, count: *mut u64) -> i32 {
+    ffi_call(|| {
+        let _db = get_db(w)?;
+        if count.is_null() {
+            return Err(Error::InvalidArgument("count is null".to_string()));
+        }
+        unsafe { count.write(_db.backpressure_count()) };
+        Ok(())
+    })
+}
+
+/// 把当前的 metrics 拷贝到 snapshot, 调用方先把 snapshot->size 设置为 sizeof(MetricsSnapshot),
+/// 只写入 size 以内的字段
+#[no_mangle]
+pub extern "C" fn metrics_snapshot(w: *mut Writer, snapshot: *mut MetricsSnapshot) -> i32 {
+    ffi_call(|| {
+        let _db = get_db(w)?;
+        if snapshot.is_null() {
+            return Err(Error::InvalidArgument("snapshot is null".to_string()));
+        }
+        let size = unsafe { (*snapshot).size } as usize;
+        if size < std::mem::size_of::<u32>() {
+            return Err(Error::InvalidArgument(format!(
+                "snapshot size {} is too small",
+                size
+            )));
+        }
+        let value = _db.metrics_snapshot();
+        let n = size.min(std::mem::size_of::<MetricsSnapshot>());
+        // 保留调用方的 size, 其余字段按调用方的长度拷贝
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                (&value as *const MetricsSnapshot as *const u8).add(4),
+                (snapshot as *mut u8).add(4),
+                n - 4,
+            )
+        };
+        Ok(())
+    })
 }
 
+/// 当前线程最近一次失败调用的错误信息, 没有错误时返回 NULL
 #[no_mangle]
-pub extern "C" fn close_db(w: *mut Writer) {
-    let _db = unsafe { &*(*w)._db.clone() };
-    _db.close_db();
+pub extern "C" fn last_error_message() -> *const c_char {
+    last_error_ptr()
 }
 
 #[cfg(target_feature = "avx2")]
@@ -61,7 +432,7 @@ pub fn hello() {
 //     let event = Event {
 //         event_type: 1,
 //         account_id: 1 as u64,
-//         strategy_id: 2 as u64,
+//         strategy_id: 2,
 //         coin: unsafe { coin.as_ptr() as *const libc::c_char },
 //         amount: unsafe { amount.as_ptr() as *const libc::c_char },
 //         trace_id: unsafe { trace_id.as_ptr() as *const libc::c_char },
@@ -76,39 +447,116 @@ pub fn hello() {
 mod tests {
     use super::db::*;
     use super::*;
-    use chrono::{Local, Utc};
     use std::ffi::{CStr, CString};
     #[test]
     fn a() {
         println!("11");
     }
 
-    fn signal_close() {
-        let mut db = Arc::new(Db::init(
-            "/Users/zuoxiaoliang/project/rust/experence_persist/db",
-        ));
+    #[test]
+    fn ffi_call_catches_panic() {
+        let code = ffi_call(|| panic!("boom"));
+        assert_eq!(code, error::ERR_PANIC);
+        let msg = unsafe { CStr::from_ptr(last_error_message()) };
+        assert_eq!(msg.to_str().unwrap(), "panic: boom");
+
+        assert_eq!(ffi_call(|| Ok(())), OK);
+        assert!(last_error_message().is_null());
+    }
+
+    #[test]
+    fn write_db_batch_reports_first_failure() {
+        let root = std::env::temp_dir().join(format!("batch_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let mut w = Writer {
+            _db: std::ptr::null_mut(),
+        };
+        open_db(
+            &mut w,
+            Options {
+                root: root.to_str().unwrap().to_string(),
+                store: config::StoreKind::Noop,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        let coin = CString::new("usdt").unwrap();
+        let amount = CString::new("12.5").unwrap();
+        let bad_amount = CString::new("abc").unwrap();
+        let trace_id = CString::new("traceId").unwrap();
+        let events = (0..5)
+            .map(|i| Event {
+                event_type: 1,
+                account_id: i,
+                strategy_id: 2,
+                coin: coin.as_ptr(),
+                amount: if i == 2 {
+                    bad_amount.as_ptr()
+                } else {
+                    amount.as_ptr()
+                },
+                trace_id: if i == 3 {
+                    std::ptr::null()
+                } else {
+                    trace_id.as_ptr()
+                },
+            })
+            .collect::<Vec<_>>();
+        let (mut accepted, mut first_failed) = (0, 0);
+        let code = write_db_batch(
+            &mut w,
+            events.as_ptr(),
+            events.len(),
+            &mut accepted,
+            &mut first_failed,
+        );
+        assert_eq!(code, error::ERR_INVALID_AMOUNT);
+        assert_eq!((accepted, first_failed), (3, 2));
+        assert_eq!(close_db(&mut w), OK);
+    }
+
+    #[test]
+    fn write_db_rejects_null_writer() {
+        let code = write_db(std::ptr::null_mut(), std::ptr::null());
+        assert_eq!(code, error::ERR_INVALID_ARGUMENT);
+    }
+
+    fn signal_close(root: &str, count: u64) {
+        let db = Arc::new(
+            Db::init(Options {
+                root: root.to_string(),
+                store: config::StoreKind::Noop,
+                ..Options::default()
+            })
+            .unwrap(),
+        );
         println!("init db success\n");
         let coin = CString::new("usdt").unwrap();
         let amount = CString::new("1234").unwrap();
         let trace_id = CString::new("traceId").unwrap();
 
-        let event = Event {
-            event_type: 1,
-            account_id: 1 as u64,
-            strategy_id: 2 as u64,
-            coin: unsafe { coin.as_ptr() as *const libc::c_char },
-            amount: unsafe { amount.as_ptr() as *const libc::c_char },
-            trace_id: unsafe { trace_id.as_ptr() as *const libc::c_char },
-        };
         println!("db start write \n");
-        // db.write(&event as *const Event);
-        // db.close();
-        // db.close_db();
+        for i in 0..count {
+            let event = Event {
+                event_type: 1,
+                account_id: i,
+                strategy_id: 2,
+                coin: coin.as_ptr() as *const libc::c_char,
+                amount: amount.as_ptr() as *const libc::c_char,
+                trace_id: trace_id.as_ptr() as *const libc::c_char,
+            };
+            db.write(&event as *const Event).unwrap();
+        }
+        db.close_db().unwrap();
     }
 
     #[test]
     fn signal_thread() {
         hello();
+        let root = std::env::temp_dir().join(format!("signal_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let root = root.to_str().unwrap();
+        signal_close(root, 10 * 1000);
 
         // let mut db = Arc::new(Db::init(
         //     "~/project/rust/xxx/db",
@@ -147,10 +595,13 @@ mod tests {
         // db.close();
         // let end = Utc::now().timestamp_millis();
         // println!("cost {:?}", (end - now));
-        let size = ParFile::read_file(
-            "/Users/zuoxiaoliang/project/rust/experence_persist/db/20230316_17_1.parquet",
-        );
+        let size: usize = std::fs::read_dir(root)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "parquet"))
+            .map(|path| ParFile::read_file(path.to_str().unwrap()))
+            .sum();
         println!("all record size  {} \n", size);
-        assert_eq!(size, 10 * 1000 as usize, "size shoud be 10*1000")
+        assert_eq!(size, 10 * 1000, "size shoud be 10*1000")
     }
 }
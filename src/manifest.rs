@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use super::error::Result;
+use log::warn;
+
+const MANIFEST_FILE: &str = "manifest.log";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileState {
+    // 正在写入
+    Open,
+    // 已经写完 footer, 等待上传
+    Sealed,
+    Uploading,
+    Uploaded,
+    // 上传重试次数用完, 下次唤醒或者重启时再传
+    Failed,
+}
+
+impl FileState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FileState::Open => "open",
+            FileState::Sealed => "sealed",
+            FileState::Uploading => "uploading",
+            FileState::Uploaded => "uploaded",
+            FileState::Failed => "failed",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "open" => Some(FileState::Open),
+            "sealed" => Some(FileState::Sealed),
+            "uploading" => Some(FileState::Uploading),
+            "uploaded" => Some(FileState::Uploaded),
+            "failed" => Some(FileState::Failed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub state: FileState,
+    pub key: String,
+    pub etag: String,
+    // 上传前计算的本地文件校验和 (hex)
+    pub md5: String,
+    pub crc32c: String,
+}
+
+impl Entry {
+    pub fn new(state: FileState) -> Self {
+        Entry {
+            state,
+            key: String::new(),
+            etag: String::new(),
+            md5: String::new(),
+            crc32c: String::new(),
+        }
+    }
+}
+
+/// 记录每个本地文件上传状态的 journal, 每次变更追加一行:
+/// `state \t file_name \t s3_key \t etag \t md5 \t crc32c`
+/// 同一个文件以最后一行为准, 打开时会压缩成每个文件一行。
+pub struct Manifest {
+    file: File,
+    entries: HashMap<String, Entry>,
+}
+
+impl Manifest {
+    pub fn open(root: &str) -> Result<Self> {
+        let path = Path::new(root).join(MANIFEST_FILE);
+        let mut entries = HashMap::new();
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path)?);
+            for line in reader.lines() {
+                let line = line?;
+                match parse_line(line.as_str()) {
+                    Some((file_name, entry)) => {
+                        entries.insert(file_name, entry);
+                    }
+                    // 崩溃时最后一行可能不完整
+                    None => warn!("manifest {:?} skip bad line {:?}", path, line),
+                }
+            }
+        }
+
+        let tmp = path.with_extension("log.tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            let mut names = entries.keys().collect::<Vec<_>>();
+            names.sort();
+            for name in names {
+                file.write_all(format_line(name, &entries[name]).as_bytes())?;
+            }
+            file.sync_data()?;
+        }
+        fs::rename(&tmp, &path)?;
+
+        let file = OpenOptions::new().append(true).open(&path)?;
+        Ok(Manifest { file, entries })
+    }
+
+    pub fn get(&self, file_name: &str) -> Option<&Entry> {
+        self.entries.get(base_name(file_name))
+    }
+
+    pub fn set_state(&mut self, file_name: &str, state: FileState) -> Result<()> {
+        let mut entry = self
+            .get(file_name)
+            .cloned()
+            .unwrap_or_else(|| Entry::new(state));
+        entry.state = state;
+        self.set(file_name, entry)
+    }
+
+    pub fn set(&mut self, file_name: &str, entry: Entry) -> Result<()> {
+        let name = base_name(file_name);
+        self.file.write_all(format_line(name, &entry).as_bytes())?;
+        self.file.sync_data()?;
+        self.entries.insert(name.to_string(), entry);
+        Ok(())
+    }
+}
+
+pub fn base_name(file_name: &str) -> &str {
+    file_name.rsplit('/').next().unwrap_or(file_name)
+}
+
+fn format_line(file_name: &str, entry: &Entry) -> String {
+    format!(
+        "{}\t{}\t{}\t{}\t{}\t{}\n",
+        entry.state.as_str(),
+        file_name,
+        entry.key.as_str(),
+        entry.etag.as_str(),
+        entry.md5.as_str(),
+        entry.crc32c.as_str()
+    )
+}
+
+fn parse_line(line: &str) -> Option<(String, Entry)> {
+    let fields = line.split('\t').collect::<Vec<_>>();
+    // 旧版本只有前 4 列
+    if (fields.len() != 4 && fields.len() != 6) || fields[1].is_empty() {
+        return None;
+    }
+    let field = |i: usize| fields.get(i).map(|f| f.to_string()).unwrap_or_default();
+    Some((
+        fields[1].to_string(),
+        Entry {
+            state: FileState::parse(fields[0])?,
+            key: field(2),
+            etag: field(3),
+            md5: field(4),
+            crc32c: field(5),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_state_wins_after_reopen() {
+        let root = std::env::temp_dir().join(format!("manifest_test_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let root_str = root.to_str().unwrap();
+        {
+            let mut manifest = Manifest::open(root_str).unwrap();
+            manifest
+                .set_state("/data/20230316_17_1.parquet", FileState::Sealed)
+                .unwrap();
+            manifest
+                .set(
+                    "/data/20230316_17_1.parquet",
+                    Entry {
+                        state: FileState::Uploaded,
+                        key: "experience_coupon/20230316/17/1.parquet".to_string(),
+                        etag: "\"abc\"".to_string(),
+                        md5: "abc".to_string(),
+                        crc32c: "0000abcd".to_string(),
+                    },
+                )
+                .unwrap();
+            manifest
+                .set_state("/data/20230316_18_1.parquet", FileState::Open)
+                .unwrap();
+        }
+
+        let manifest = Manifest::open(root_str).unwrap();
+        let entry = manifest.get("20230316_17_1.parquet").unwrap();
+        assert_eq!(entry.state, FileState::Uploaded);
+        assert_eq!(entry.key, "experience_coupon/20230316/17/1.parquet");
+        assert_eq!(entry.md5, "abc");
+        assert_eq!(entry.crc32c, "0000abcd");
+        assert_eq!(
+            manifest.get("20230316_18_1.parquet").unwrap().state,
+            FileState::Open
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
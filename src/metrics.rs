@@ -0,0 +1,414 @@
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::db::Db;
+use super::error::Result;
+use super::rotation::Reason;
+use log::{error, info};
+
+const EVENT_TYPES: [&str; 3] = ["trade", "balance_change", "settle_fee"];
+const REASONS: [&str; 4] = ["window", "size", "rows", "idle"];
+
+// 每一批的行数
+const BATCH_ROWS_BUCKETS: &[u64] = &[1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1024, 4096, 16384];
+// 延迟, 单位微秒, 输出时换算成秒
+const APPEND_LATENCY_BUCKETS: &[u64] = &[
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 1_000_000,
+];
+const UPLOAD_LATENCY_BUCKETS: &[u64] = &[
+    10_000, 50_000, 100_000, 250_000, 500_000, 1_000_000, 2_500_000, 5_000_000, 10_000_000,
+    30_000_000, 60_000_000,
+];
+
+/// 固定桶的直方图, 值和 sum 用整数保存, 输出时除以 scale
+pub struct Histogram {
+    bounds: &'static [u64],
+    // 最后一个是 +Inf
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+    count: AtomicU64,
+    scale: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [u64], scale: f64) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+            scale,
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        let i = self
+            .bounds
+            .iter()
+            .position(|b| value <= *b)
+            .unwrap_or(self.bounds.len());
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe_duration(&self, value: Duration) {
+        self.observe(value.as_micros() as u64);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = match self.bounds.get(i) {
+                Some(bound) => format!("{}", *bound as f64 / self.scale),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+        }
+        let _ = writeln!(out, "{}_sum {}", name, self.sum() as f64 / self.scale);
+        let _ = writeln!(out, "{}_count {}", name, self.count());
+    }
+}
+
+/// db 内部的计数器, 所有方法都可以并发调用
+pub struct Metrics {
+    events_written: [AtomicU64; 3],
+    events_failed: AtomicU64,
+    bytes_written: AtomicU64,
+    batch_rows: Histogram,
+    append_latency: Histogram,
+    files_rotated: [AtomicU64; 4],
+    upload_attempts: AtomicU64,
+    upload_failures: AtomicU64,
+    upload_latency: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            events_written: Default::default(),
+            events_failed: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            batch_rows: Histogram::new(BATCH_ROWS_BUCKETS, 1.0),
+            append_latency: Histogram::new(APPEND_LATENCY_BUCKETS, 1e6),
+            files_rotated: Default::default(),
+            upload_attempts: AtomicU64::new(0),
+            upload_failures: AtomicU64::new(0),
+            upload_latency: Histogram::new(UPLOAD_LATENCY_BUCKETS, 1e6),
+        }
+    }
+}
+
+/// 导出给 C 的快照, 调用方把 size 设置为 sizeof(MetricsSnapshot),
+/// 以后新增的字段只追加在末尾, 旧的调用方只拿到 size 以内的字段
+#[repr(C)]
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MetricsSnapshot {
+    pub size: u32,
+    pub events_trade: u64,
+    pub events_balance_change: u64,
+    pub events_settle_fee: u64,
+    pub events_failed: u64,
+    pub bytes_written: u64,
+    pub batches: u64,
+    pub batch_rows_sum: u64,
+    pub appends: u64,
+    pub append_latency_us_sum: u64,
+    pub files_rotated_window: u64,
+    pub files_rotated_size: u64,
+    pub files_rotated_rows: u64,
+    pub files_rotated_idle: u64,
+    pub upload_attempts: u64,
+    pub upload_failures: u64,
+    pub upload_latency_us_sum: u64,
+    pub pending_uploads: u64,
+    pub backpressure: u64,
+}
+
+impl Metrics {
+    /// 一批写入成功, rows 按 event_type 统计
+    pub fn record_append(&self, rows: &[u64; 3], bytes: u64, latency: Duration) {
+        for (counter, n) in self.events_written.iter().zip(rows) {
+            counter.fetch_add(*n, Ordering::Relaxed);
+        }
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+        self.batch_rows.observe(rows.iter().sum());
+        self.append_latency.observe_duration(latency);
+    }
+
+    pub fn record_failed(&self, rows: u64) {
+        self.events_failed.fetch_add(rows, Ordering::Relaxed);
+    }
+
+    pub fn record_rotate(&self, reason: Reason) {
+        self.files_rotated[reason_index(reason)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_upload(&self, ok: bool, latency: Duration) {
+        self.upload_attempts.fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            self.upload_failures.fetch_add(1, Ordering::Relaxed);
+        }
+        self.upload_latency.observe_duration(latency);
+    }
+
+    /// pending_uploads 和 backpressure 由 db 提供
+    pub fn snapshot(&self, pending_uploads: u64, backpressure: u64) -> MetricsSnapshot {
+        let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+        MetricsSnapshot {
+            size: std::mem::size_of::<MetricsSnapshot>() as u32,
+            events_trade: load(&self.events_written[0]),
+            events_balance_change: load(&self.events_written[1]),
+            events_settle_fee: load(&self.events_written[2]),
+            events_failed: load(&self.events_failed),
+            bytes_written: load(&self.bytes_written),
+            batches: self.batch_rows.count(),
+            batch_rows_sum: self.batch_rows.sum(),
+            appends: self.append_latency.count(),
+            append_latency_us_sum: self.append_latency.sum(),
+            files_rotated_window: load(&self.files_rotated[0]),
+            files_rotated_size: load(&self.files_rotated[1]),
+            files_rotated_rows: load(&self.files_rotated[2]),
+            files_rotated_idle: load(&self.files_rotated[3]),
+            upload_attempts: load(&self.upload_attempts),
+            upload_failures: load(&self.upload_failures),
+            upload_latency_us_sum: self.upload_latency.sum(),
+            pending_uploads,
+            backpressure,
+        }
+    }
+
+    /// Prometheus text exposition 格式
+    pub fn render(&self, pending_uploads: u64, backpressure: u64) -> String {
+        let mut out = String::new();
+        let counter = |out: &mut String, name: &str, help: &str, values: &[(String, u64)]| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for (labels, value) in values {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        };
+        let labeled = |label: &str, names: &[&str], values: &[AtomicU64]| {
+            names
+                .iter()
+                .zip(values)
+                .map(|(name, v)| {
+                    (
+                        format!("{{{}=\"{}\"}}", label, name),
+                        v.load(Ordering::Relaxed),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let plain = |v: &AtomicU64| vec![(String::new(), v.load(Ordering::Relaxed))];
+
+        counter(
+            &mut out,
+            "events_written_total",
+            "Events written to wal and parquet.",
+            &labeled("event_type", &EVENT_TYPES, &self.events_written),
+        );
+        counter(
+            &mut out,
+            "events_failed_total",
+            "Events rejected or failed to write.",
+            &plain(&self.events_failed),
+        );
+        counter(
+            &mut out,
+            "bytes_written_total",
+            "Encoded bytes appended to the wal.",
+            &plain(&self.bytes_written),
+        );
+        self.batch_rows.render(
+            &mut out,
+            "group_commit_batch_rows",
+            "Rows written by one group commit.",
+        );
+        self.append_latency.render(
+            &mut out,
+            "append_latency_seconds",
+            "Time to append one group commit to wal and parquet.",
+        );
+        counter(
+            &mut out,
+            "files_rotated_total",
+            "Files sealed, by reason.",
+            &labeled("reason", &REASONS, &self.files_rotated),
+        );
+        counter(
+            &mut out,
+            "upload_attempts_total",
+            "Upload attempts, including retries.",
+            &plain(&self.upload_attempts),
+        );
+        counter(
+            &mut out,
+            "upload_failures_total",
+            "Failed upload attempts.",
+            &plain(&self.upload_failures),
+        );
+        self.upload_latency.render(
+            &mut out,
+            "upload_latency_seconds",
+            "Time of one upload attempt.",
+        );
+        let _ = writeln!(
+            out,
+            "# HELP pending_uploads Sealed files waiting for upload."
+        );
+        let _ = writeln!(out, "# TYPE pending_uploads gauge");
+        let _ = writeln!(out, "pending_uploads {}", pending_uploads);
+        counter(
+            &mut out,
+            "backpressure_total",
+            "Writes that hit the queue limit.",
+            &[(String::new(), backpressure)],
+        );
+        out
+    }
+}
+
+fn reason_index(reason: Reason) -> usize {
+    match reason {
+        Reason::Window => 0,
+        Reason::Size => 1,
+        Reason::Rows => 2,
+        Reason::Idle => 3,
+    }
+}
+
+// 没有请求时多久检查一次是否要退出
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// metrics endpoint 的后台线程, stop 之后端口立即释放
+pub struct Server {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl Server {
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.handle.join();
+    }
+}
+
+/// 在 addr 上提供 GET /metrics, 只持有 Db 的 Weak, 由 Db::close 调用 stop 退出
+pub fn serve(addr: &str, db: Weak<Db>) -> Result<Server> {
+    let listener = TcpListener::bind(addr)?;
+    // accept 不阻塞, 这样线程能看到 stop
+    listener.set_nonblocking(true)?;
+    info!("metrics endpoint listen on {}", addr);
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    let handle = std::thread::spawn(move || {
+        while !stopped.load(Ordering::SeqCst) {
+            let mut stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(ACCEPT_INTERVAL);
+                    continue;
+                }
+                Err(e) => {
+                    error!("metrics endpoint accept failed {:?}", e);
+                    std::thread::sleep(ACCEPT_INTERVAL);
+                    continue;
+                }
+            };
+            let body = match db.upgrade() {
+                Some(db) => db.metrics_text(),
+                None => return,
+            };
+            // 不解析请求, 任何路径都返回 metrics
+            let _ = stream.set_nonblocking(false);
+            let mut buf = [0u8; 1024];
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+            let _ = stream.read(&mut buf);
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            if let Err(e) = stream.write_all(response.as_bytes()) {
+                error!("metrics endpoint write failed {:?}", e);
+            }
+        }
+    });
+    Ok(Server { stop, handle })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_counters_and_histograms() {
+        let metrics = Metrics::default();
+        metrics.record_append(&[2, 1, 0], 300, Duration::from_micros(800));
+        metrics.record_rotate(Reason::Size);
+        metrics.record_upload(false, Duration::from_millis(20));
+
+        let text = metrics.render(3, 1);
+        assert!(text.contains("events_written_total{event_type=\"trade\"} 2"));
+        assert!(text.contains("files_rotated_total{reason=\"size\"} 1"));
+        assert!(text.contains("group_commit_batch_rows_bucket{le=\"4\"} 1"));
+        assert!(text.contains("append_latency_seconds_bucket{le=\"0.001\"} 1"));
+        assert!(text.contains("append_latency_seconds_bucket{le=\"0.0005\"} 0"));
+        assert!(text.contains("upload_failures_total 1"));
+        assert!(text.contains("pending_uploads 3"));
+
+        let snapshot = metrics.snapshot(3, 1);
+        assert_eq!(snapshot.batch_rows_sum, 3);
+        assert_eq!(snapshot.append_latency_us_sum, 800);
+        assert_eq!(snapshot.backpressure, 1);
+    }
+
+    #[test]
+    fn endpoint_stops_on_close() {
+        use crate::config::{Options, StoreKind};
+        use std::net::TcpStream;
+
+        let root = std::env::temp_dir().join(format!("metrics_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let options = Options {
+            root: root.to_str().unwrap().to_string(),
+            store: StoreKind::Noop,
+            ..Options::default()
+        };
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+
+        for _ in 0..2 {
+            // 第二次能绑定同一个端口说明上一次已经释放
+            let db = Arc::new(Db::init(options.clone()).unwrap());
+            db.serve_metrics(addr.as_str()).unwrap();
+            let mut stream = TcpStream::connect(addr.as_str()).unwrap();
+            stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+            assert!(response.contains("pending_uploads 0"));
+            db.close_db().unwrap();
+        }
+    }
+}
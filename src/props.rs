@@ -0,0 +1,151 @@
+use parquet::basic::{Compression, GzipLevel, ZstdLevel};
+use parquet::file::metadata::KeyValue;
+use parquet::file::properties::{EnabledStatistics, WriterProperties};
+use parquet::schema::types::ColumnPath;
+
+use super::error::{Error, Result};
+
+// 写到 key-value metadata 里的 writer 配置, 方便排查文件是怎么写出来的
+const COMPRESSION_KEY: &str = "writer.compression";
+const STATISTICS_KEY: &str = "writer.statistics";
+const BLOOM_FILTER_KEY: &str = "writer.bloom_filter";
+const MAX_ROW_GROUP_SIZE_KEY: &str = "writer.max_row_group_size";
+
+// 取值少的列用字典编码, trace_id 这种几乎不重复的列关掉字典
+const DICTIONARY_COLUMNS: &[&str] = &["coin", "fee_coin"];
+const PLAIN_COLUMNS: &[&str] = &["trace_id"];
+// 按账户和 trace_id 查找时用 bloom filter 跳过 row group
+const BLOOM_FILTER_COLUMNS: &[&str] = &["account_id", "trace_id"];
+
+/// parquet writer 的配置
+#[derive(Debug, Clone)]
+pub struct WriterOptions {
+    pub compression: Compression,
+    pub statistics: EnabledStatistics,
+    pub bloom_filter: bool,
+    pub max_row_group_size: usize,
+}
+
+impl Default for WriterOptions {
+    fn default() -> Self {
+        WriterOptions {
+            compression: Compression::UNCOMPRESSED,
+            statistics: EnabledStatistics::Page,
+            bloom_filter: true,
+            max_row_group_size: 1024 * 1024,
+        }
+    }
+}
+
+impl WriterOptions {
+    /// compression: 0 不压缩, 1 snappy, 2 gzip, 3 lz4, 4 zstd;
+    /// level 为 0 时使用默认级别, 只有 gzip 和 zstd 支持级别
+    pub fn compression(compression: u8, level: i32) -> Result<Compression> {
+        let invalid = |e: String| {
+            Error::InvalidArgument(format!(
+                "compression {} level {} is not support: {}",
+                compression, level, e
+            ))
+        };
+        Ok(match (compression, level) {
+            (0, 0) => Compression::UNCOMPRESSED,
+            (1, 0) => Compression::SNAPPY,
+            (2, 0) => Compression::GZIP(GzipLevel::default()),
+            (2, level) => Compression::GZIP(
+                GzipLevel::try_new(level as u32).map_err(|e| invalid(e.to_string()))?,
+            ),
+            (3, 0) => Compression::LZ4,
+            (4, 0) => Compression::ZSTD(ZstdLevel::default()),
+            (4, level) => {
+                Compression::ZSTD(ZstdLevel::try_new(level).map_err(|e| invalid(e.to_string()))?)
+            }
+            (0..=4, _) => return Err(invalid("no compression level".to_string())),
+            _ => return Err(invalid("unknown codec".to_string())),
+        })
+    }
+
+    /// statistics: 0 page, 1 chunk, 2 不写统计信息
+    pub fn statistics(statistics: u8) -> Result<EnabledStatistics> {
+        match statistics {
+            0 => Ok(EnabledStatistics::Page),
+            1 => Ok(EnabledStatistics::Chunk),
+            2 => Ok(EnabledStatistics::None),
+            other => Err(Error::InvalidArgument(format!(
+                "statistics {} is not support",
+                other
+            ))),
+        }
+    }
+
+    pub fn key_values(&self) -> Vec<KeyValue> {
+        vec![
+            KeyValue::new(
+                COMPRESSION_KEY.to_string(),
+                format!("{:?}", self.compression),
+            ),
+            KeyValue::new(STATISTICS_KEY.to_string(), format!("{:?}", self.statistics)),
+            KeyValue::new(BLOOM_FILTER_KEY.to_string(), self.bloom_filter.to_string()),
+            KeyValue::new(
+                MAX_ROW_GROUP_SIZE_KEY.to_string(),
+                self.max_row_group_size.to_string(),
+            ),
+        ]
+    }
+
+    /// metadata 是额外写到文件里的 key-value, 例如 schema 版本
+    pub fn build(&self, mut metadata: Vec<KeyValue>) -> WriterProperties {
+        metadata.extend(self.key_values());
+        let mut builder = WriterProperties::builder()
+            .set_compression(self.compression)
+            .set_statistics_enabled(self.statistics)
+            .set_max_row_group_size(self.max_row_group_size)
+            .set_key_value_metadata(Some(metadata));
+        for column in DICTIONARY_COLUMNS {
+            builder = builder.set_column_dictionary_enabled(ColumnPath::from(*column), true);
+        }
+        for column in PLAIN_COLUMNS {
+            builder = builder.set_column_dictionary_enabled(ColumnPath::from(*column), false);
+        }
+        if self.bloom_filter {
+            for column in BLOOM_FILTER_COLUMNS {
+                builder = builder.set_column_bloom_filter_enabled(ColumnPath::from(*column), true);
+            }
+        }
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compression_level() {
+        assert_eq!(
+            WriterOptions::compression(4, 3).unwrap(),
+            Compression::ZSTD(ZstdLevel::try_new(3).unwrap())
+        );
+        assert_eq!(
+            WriterOptions::compression(1, 0).unwrap(),
+            Compression::SNAPPY
+        );
+        assert!(WriterOptions::compression(1, 3).is_err());
+        assert!(WriterOptions::compression(4, 100).is_err());
+        assert!(WriterOptions::compression(5, 0).is_err());
+    }
+
+    #[test]
+    fn build_records_options_in_metadata() {
+        let options = WriterOptions {
+            compression: Compression::ZSTD(ZstdLevel::try_new(3).unwrap()),
+            ..WriterOptions::default()
+        };
+        let props = options.build(vec![KeyValue::new("k".to_string(), "v".to_string())]);
+        let kvs = props.key_value_metadata().unwrap();
+        assert_eq!(kvs[0].key, "k");
+        assert!(kvs.iter().any(|kv| kv.key == COMPRESSION_KEY));
+        assert!(props.dictionary_enabled(&ColumnPath::from("coin")));
+        assert!(!props.dictionary_enabled(&ColumnPath::from("trace_id")));
+        assert_eq!(props.max_row_group_size(), 1024 * 1024);
+    }
+}
@@ -0,0 +1,641 @@
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::path::Path;
+
+use arrow::array::{
+    Array, ArrayRef, BooleanArray, Decimal128Array, StringArray, TimestampMicrosecondArray,
+    UInt64Array, UInt8Array,
+};
+use arrow::datatypes::DataType;
+use arrow::record_batch::RecordBatch;
+use log::{debug, warn};
+use parquet::arrow::arrow_reader::{ArrowPredicateFn, ParquetRecordBatchReaderBuilder, RowFilter};
+use parquet::arrow::ProjectionMask;
+use parquet::data_type::ByteArray;
+use parquet::file::metadata::RowGroupMetaData;
+use parquet::file::properties::ReaderProperties;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::file::serialized_reader::ReadOptionsBuilder;
+use parquet::file::statistics::Statistics;
+
+use super::db::c_string_opt;
+use super::decimal;
+use super::error::Result;
+
+/// 查询条件, None 表示不过滤。
+/// 时间范围 [start_us, end_us) 按 event_time 过滤, 没有 event_time 的行使用 ingest_time
+#[derive(Debug, Default, Clone)]
+pub struct Filter {
+    pub account_id: Option<u64>,
+    pub strategy_id: Option<u64>,
+    pub event_type: Option<u8>,
+    pub coin: Option<String>,
+    pub start_us: Option<i64>,
+    pub end_us: Option<i64>,
+    // 有 bloom filter 的文件先用 bloom filter 跳过 row group
+    pub trace_id: Option<String>,
+}
+
+/// 查询出来的一行, 金额类字段转换成十进制字符串, 旧版本文件里没有的列为 None
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventRow {
+    pub file_name: String,
+    pub event_type: u8,
+    pub account_id: u64,
+    pub strategy_id: u64,
+    pub coin: String,
+    pub amount: String,
+    pub trace_id: String,
+    pub fee_coin: Option<String>,
+    pub fee_amount: Option<String>,
+    pub event_time_us: Option<i64>,
+    pub ingest_time_us: Option<i64>,
+    pub trade_price: Option<String>,
+    pub trade_qty: Option<String>,
+    pub trade_side: Option<u8>,
+    pub fee_rate: Option<String>,
+    pub duplicate: bool,
+}
+
+// 谓词用到的列, 旧文件里可能没有时间列
+const FILTER_COLUMNS: &[&str] = &[
+    "account_id",
+    "strategy_id",
+    "event_type",
+    "coin",
+    "event_time",
+    "ingest_time",
+];
+
+const TRACE_ID_COLUMN: &str = "trace_id";
+
+/// 按文件名顺序查询 root 下所有写完 footer 的 parquet 文件,
+/// 正在写入的文件没有 footer, 查不到。f 返回 false 时停止
+pub fn query<F: FnMut(EventRow) -> bool>(root: &str, filter: &Filter, mut f: F) -> Result<()> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_file() && file_name.ends_with(".parquet") {
+            names.push(file_name);
+        }
+    }
+    names.sort();
+    for name in names {
+        let path = Path::new(root).join(name.as_str());
+        match query_file(path.to_str().unwrap_or_default(), filter, &mut f) {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(e) => warn!("query skip file {:?} {}", path, e),
+        }
+    }
+    Ok(())
+}
+
+/// 返回 false 表示调用方要求停止
+pub fn query_file<F: FnMut(EventRow) -> bool>(
+    file_name: &str,
+    filter: &Filter,
+    f: &mut F,
+) -> Result<bool> {
+    let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(file_name)?)?;
+
+    let columns = builder.parquet_schema().columns();
+    let index_of = |name: &str| columns.iter().position(|c| c.name() == name);
+    let mut row_groups = builder
+        .metadata()
+        .row_groups()
+        .iter()
+        .enumerate()
+        .filter(|(_, rg)| may_match(rg, filter, &index_of))
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    if let (Some(trace_id), Some(column)) = (&filter.trace_id, index_of(TRACE_ID_COLUMN)) {
+        if !row_groups.is_empty() {
+            row_groups = bloom_filter_match(file_name, row_groups, column, trace_id.as_str())?;
+        }
+    }
+    debug!(
+        "query file {} row groups {:?} of {}",
+        file_name,
+        row_groups,
+        builder.metadata().num_row_groups()
+    );
+    if row_groups.is_empty() {
+        return Ok(true);
+    }
+
+    let mut leaves = FILTER_COLUMNS
+        .iter()
+        .filter_map(|name| index_of(name))
+        .collect::<Vec<_>>();
+    if filter.trace_id.is_some() {
+        leaves.extend(index_of(TRACE_ID_COLUMN));
+    }
+    let mask = ProjectionMask::leaves(builder.parquet_schema(), leaves);
+    let predicate_filter = filter.clone();
+    let predicate = ArrowPredicateFn::new(mask, move |batch: RecordBatch| {
+        Ok(matches(&batch, &predicate_filter))
+    });
+    let reader = builder
+        .with_row_groups(row_groups)
+        .with_row_filter(RowFilter::new(vec![Box::new(predicate)]))
+        .build()?;
+
+    let base_name = file_name.rsplit('/').next().unwrap_or(file_name);
+    for batch in reader {
+        let batch = batch?;
+        for row in 0..batch.num_rows() {
+            if !f(event_row(base_name, &batch, row)) {
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+// 用 bloom filter 排除一定不包含 trace_id 的 row group, 没有 bloom filter 的 row group 保留
+fn bloom_filter_match(
+    file_name: &str,
+    row_groups: Vec<usize>,
+    column: usize,
+    trace_id: &str,
+) -> Result<Vec<usize>> {
+    let props = ReaderProperties::builder()
+        .set_read_bloom_filter(true)
+        .build();
+    let options = ReadOptionsBuilder::new()
+        .with_reader_properties(props)
+        .build();
+    let reader = SerializedFileReader::new_with_options(File::open(file_name)?, options)?;
+    // 写入时 bloom filter 按 ByteArray 的字节计算 hash
+    let trace_id = ByteArray::from(trace_id);
+    let mut matched = Vec::with_capacity(row_groups.len());
+    for i in row_groups {
+        let row_group = reader.get_row_group(i)?;
+        if row_group
+            .get_column_bloom_filter(column)
+            .is_none_or(|bloom| bloom.check(&trace_id))
+        {
+            matched.push(i);
+        }
+    }
+    Ok(matched)
+}
+
+// 用 row group 的统计信息判断是否可能有满足条件的行
+fn may_match(
+    rg: &RowGroupMetaData,
+    filter: &Filter,
+    index_of: &dyn Fn(&str) -> Option<usize>,
+) -> bool {
+    let stats = |name: &str| index_of(name).and_then(|i| rg.column(i).statistics());
+    let contains = |name: &str, value: u64| match stats(name) {
+        // 无符号列按位存成 INT64 / INT32
+        Some(Statistics::Int64(s)) if s.has_min_max_set() => {
+            (*s.min() as u64) <= value && value <= (*s.max() as u64)
+        }
+        Some(Statistics::Int32(s)) if s.has_min_max_set() => {
+            (*s.min() as u32 as u64) <= value && value <= (*s.max() as u32 as u64)
+        }
+        _ => true,
+    };
+    if matches!(filter.account_id, Some(id) if !contains("account_id", id)) {
+        return false;
+    }
+    if matches!(filter.strategy_id, Some(id) if !contains("strategy_id", id)) {
+        return false;
+    }
+    if matches!(filter.event_type, Some(t) if !contains("event_type", t as u64)) {
+        return false;
+    }
+    // event_time 没有 null 时才能只看它的范围
+    let event_time = stats("event_time");
+    let no_null = event_time.is_some_and(|s| s.null_count() == 0);
+    match event_time {
+        Some(Statistics::Int64(s)) if s.has_min_max_set() && no_null => {
+            let start = filter.start_us.unwrap_or(i64::MIN);
+            let end = filter.end_us.unwrap_or(i64::MAX);
+            *s.max() >= start && *s.min() < end
+        }
+        _ => true,
+    }
+}
+
+fn matches(batch: &RecordBatch, filter: &Filter) -> BooleanArray {
+    let u64_column = |name: &str| {
+        batch
+            .column_by_name(name)
+            .and_then(|c| c.as_any().downcast_ref::<UInt64Array>())
+    };
+    let account_id = u64_column("account_id");
+    let strategy_id = u64_column("strategy_id");
+    let event_type = batch
+        .column_by_name("event_type")
+        .and_then(|c| c.as_any().downcast_ref::<UInt8Array>());
+    let coin = batch
+        .column_by_name("coin")
+        .and_then(|c| c.as_any().downcast_ref::<StringArray>());
+    let trace_id = batch
+        .column_by_name(TRACE_ID_COLUMN)
+        .and_then(|c| c.as_any().downcast_ref::<StringArray>());
+    let event_time = batch.column_by_name("event_time");
+    let ingest_time = batch.column_by_name("ingest_time");
+
+    (0..batch.num_rows())
+        .map(|i| {
+            let ok = match (filter.account_id, account_id) {
+                (Some(id), Some(col)) => col.value(i) == id,
+                _ => true,
+            } && match (filter.strategy_id, strategy_id) {
+                (Some(id), Some(col)) => col.value(i) == id,
+                _ => true,
+            } && match (filter.event_type, event_type) {
+                (Some(t), Some(col)) => col.value(i) == t,
+                _ => true,
+            } && match (&filter.coin, coin) {
+                (Some(c), Some(col)) => col.value(i).eq_ignore_ascii_case(c.as_str()),
+                _ => true,
+            } && match (&filter.trace_id, trace_id) {
+                (Some(t), Some(col)) => col.value(i) == t.as_str(),
+                _ => true,
+            };
+            if !ok || (filter.start_us.is_none() && filter.end_us.is_none()) {
+                return Some(ok);
+            }
+            // 旧文件没有时间列, 不能按时间过滤
+            let time = timestamp_at(event_time, i).or_else(|| timestamp_at(ingest_time, i));
+            Some(match time {
+                Some(t) => {
+                    filter.start_us.is_none_or(|s| t >= s) && filter.end_us.is_none_or(|e| t < e)
+                }
+                None => false,
+            })
+        })
+        .collect()
+}
+
+fn event_row(file_name: &str, batch: &RecordBatch, i: usize) -> EventRow {
+    let column = |name: &str| batch.column_by_name(name);
+    EventRow {
+        file_name: file_name.to_string(),
+        event_type: u8_at(column("event_type"), i).unwrap_or_default(),
+        account_id: u64_at(column("account_id"), i).unwrap_or_default(),
+        strategy_id: u64_at(column("strategy_id"), i).unwrap_or_default(),
+        coin: string_at(column("coin"), i).unwrap_or_default(),
+        amount: string_at(column("amount"), i).unwrap_or_default(),
+        trace_id: string_at(column("trace_id"), i).unwrap_or_default(),
+        fee_coin: string_at(column("fee_coin"), i),
+        fee_amount: string_at(column("fee_amount"), i),
+        event_time_us: timestamp_at(column("event_time"), i),
+        ingest_time_us: timestamp_at(column("ingest_time"), i),
+        trade_price: string_at(column("trade_price"), i),
+        trade_qty: string_at(column("trade_qty"), i),
+        trade_side: u8_at(column("trade_side"), i),
+        fee_rate: string_at(column("fee_rate"), i),
+        duplicate: bool_at(column("duplicate"), i).unwrap_or_default(),
+    }
+}
+
+fn bool_at(column: Option<&ArrayRef>, i: usize) -> Option<bool> {
+    let column = column?.as_any().downcast_ref::<BooleanArray>()?;
+    column.is_valid(i).then(|| column.value(i))
+}
+
+fn u8_at(column: Option<&ArrayRef>, i: usize) -> Option<u8> {
+    let column = column?.as_any().downcast_ref::<UInt8Array>()?;
+    column.is_valid(i).then(|| column.value(i))
+}
+
+fn u64_at(column: Option<&ArrayRef>, i: usize) -> Option<u64> {
+    let column = column?.as_any().downcast_ref::<UInt64Array>()?;
+    column.is_valid(i).then(|| column.value(i))
+}
+
+fn timestamp_at(column: Option<&ArrayRef>, i: usize) -> Option<i64> {
+    let column = column?
+        .as_any()
+        .downcast_ref::<TimestampMicrosecondArray>()?;
+    column.is_valid(i).then(|| column.value(i))
+}
+
+// Decimal128 列转换成字符串, 加入 Decimal128 之前的文件 amount 是 Utf8
+fn string_at(column: Option<&ArrayRef>, i: usize) -> Option<String> {
+    let column = column?;
+    if column.is_null(i) {
+        return None;
+    }
+    match column.data_type() {
+        DataType::Utf8 => column
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .map(|c| c.value(i).to_string()),
+        DataType::Decimal128(_, scale) => column
+            .as_any()
+            .downcast_ref::<Decimal128Array>()
+            .map(|c| decimal::format(c.value(i), *scale)),
+        _ => None,
+    }
+}
+
+/// C 侧的查询条件, has_xxx 为 0 时不过滤对应字段, coin 为 NULL 时不过滤,
+/// start_us / end_us 为 0 时不限制, trace_id 为 NULL 时不过滤
+#[repr(C)]
+#[derive(Debug)]
+pub struct EventFilter {
+    pub has_account_id: u8,
+    pub account_id: u64,
+    pub has_strategy_id: u8,
+    pub strategy_id: u64,
+    pub has_event_type: u8,
+    pub event_type: u8,
+    pub coin: *const libc::c_char,
+    pub start_us: i64,
+    pub end_us: i64,
+    pub trace_id: *const libc::c_char,
+}
+
+impl Filter {
+    pub fn from_c(filter: &EventFilter) -> Result<Self> {
+        Ok(Filter {
+            account_id: (filter.has_account_id != 0).then_some(filter.account_id),
+            strategy_id: (filter.has_strategy_id != 0).then_some(filter.strategy_id),
+            event_type: (filter.has_event_type != 0).then_some(filter.event_type),
+            coin: c_string_opt(filter.coin, "coin")?,
+            start_us: Some(filter.start_us).filter(|v| *v != 0),
+            end_us: Some(filter.end_us).filter(|v| *v != 0),
+            trace_id: c_string_opt(filter.trace_id, "trace_id")?,
+        })
+    }
+}
+
+/// 回调里拿到的一行, 字符串只在回调期间有效, 没有的字段为 NULL / 0
+#[repr(C)]
+#[derive(Debug)]
+pub struct QueryRow {
+    pub file_name: *const libc::c_char,
+    pub event_type: u8,
+    pub account_id: u64,
+    pub strategy_id: u64,
+    pub coin: *const libc::c_char,
+    pub amount: *const libc::c_char,
+    pub trace_id: *const libc::c_char,
+    pub fee_coin: *const libc::c_char,
+    pub fee_amount: *const libc::c_char,
+    pub event_time_us: i64,
+    pub ingest_time_us: i64,
+    // trade_price 不为 NULL 时 trade_side 才有意义
+    pub trade_price: *const libc::c_char,
+    pub trade_qty: *const libc::c_char,
+    pub trade_side: u8,
+    pub fee_rate: *const libc::c_char,
+    pub duplicate: u8,
+}
+
+/// 返回非 0 时停止查询
+pub type QueryCallback = extern "C" fn(row: *const QueryRow, ctx: *mut libc::c_void) -> i32;
+
+impl EventRow {
+    /// 把行转换成 QueryRow 交给 f, CString 在 f 返回之前一直有效
+    pub fn with_c<R>(&self, f: impl FnOnce(&QueryRow) -> R) -> R {
+        // 字符串里不会有 \0, 万一有就替换掉
+        let c = |s: &str| CString::new(s.replace('\0', " ")).unwrap_or_default();
+        let c_opt = |s: &Option<String>| s.as_deref().map(c);
+        let ptr = |s: &Option<CString>| s.as_ref().map_or(std::ptr::null(), |s| s.as_ptr());
+
+        let file_name = c(self.file_name.as_str());
+        let coin = c(self.coin.as_str());
+        let amount = c(self.amount.as_str());
+        let trace_id = c(self.trace_id.as_str());
+        let fee_coin = c_opt(&self.fee_coin);
+        let fee_amount = c_opt(&self.fee_amount);
+        let trade_price = c_opt(&self.trade_price);
+        let trade_qty = c_opt(&self.trade_qty);
+        let fee_rate = c_opt(&self.fee_rate);
+        f(&QueryRow {
+            file_name: file_name.as_ptr(),
+            event_type: self.event_type,
+            account_id: self.account_id,
+            strategy_id: self.strategy_id,
+            coin: coin.as_ptr(),
+            amount: amount.as_ptr(),
+            trace_id: trace_id.as_ptr(),
+            fee_coin: ptr(&fee_coin),
+            fee_amount: ptr(&fee_amount),
+            event_time_us: self.event_time_us.unwrap_or_default(),
+            ingest_time_us: self.ingest_time_us.unwrap_or_default(),
+            trade_price: ptr(&trade_price),
+            trade_qty: ptr(&trade_qty),
+            trade_side: self.trade_side.unwrap_or_default(),
+            fee_rate: ptr(&fee_rate),
+            duplicate: self.duplicate as u8,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Options, StoreKind};
+    use crate::db::{Db, EventV2};
+    use crate::dedup::DedupMode;
+    use crate::event::{Side, EVENT_BALANCE_CHANGE, EVENT_TRADE};
+    use crate::props::WriterOptions;
+
+    #[test]
+    fn query_with_filter() {
+        let root = std::env::temp_dir().join(format!("reader_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let db = Db::init(Options {
+            root: root.to_str().unwrap().to_string(),
+            store: StoreKind::Noop,
+            writer: WriterOptions {
+                max_row_group_size: 4,
+                ..WriterOptions::default()
+            },
+            ..Options::default()
+        })
+        .unwrap();
+
+        let coin = CString::new("USDT").unwrap();
+        let amount = CString::new("1.50").unwrap();
+        let price = CString::new("27000.1").unwrap();
+        let qty = CString::new("0.002").unwrap();
+        for i in 0..20u64 {
+            let trace_id = CString::new(format!("t{}", i)).unwrap();
+            let time = 1_000_000 + i as i64;
+            let event = if i % 2 == 0 {
+                EventV2::trade(
+                    i,
+                    7,
+                    coin.as_ptr(),
+                    amount.as_ptr(),
+                    trace_id.as_ptr(),
+                    price.as_ptr(),
+                    qty.as_ptr(),
+                    Side::Sell,
+                    time,
+                )
+            } else {
+                EventV2::balance_change(
+                    i,
+                    7,
+                    coin.as_ptr(),
+                    amount.as_ptr(),
+                    trace_id.as_ptr(),
+                    time,
+                )
+            };
+            db.write_v2(&event).unwrap();
+        }
+        db.close_db().unwrap();
+
+        let root = root.to_str().unwrap();
+        let mut rows = Vec::new();
+        let filter = Filter {
+            event_type: Some(EVENT_TRADE),
+            start_us: Some(1_000_004),
+            end_us: Some(1_000_010),
+            ..Filter::default()
+        };
+        query(root, &filter, |row| {
+            rows.push(row);
+            true
+        })
+        .unwrap();
+        assert_eq!(
+            rows.iter().map(|r| r.account_id).collect::<Vec<_>>(),
+            vec![4, 6, 8]
+        );
+        assert_eq!(rows[0].amount, "1.5");
+        assert_eq!(rows[0].trade_price.as_deref(), Some("27000.1"));
+        assert_eq!(rows[0].trade_side, Some(Side::Sell as u8));
+
+        let filter = Filter {
+            account_id: Some(13),
+            coin: Some("usdt".to_string()),
+            ..Filter::default()
+        };
+        let mut rows = Vec::new();
+        query(root, &filter, |row| {
+            rows.push(row);
+            true
+        })
+        .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].event_type, EVENT_BALANCE_CHANGE);
+        assert_eq!(rows[0].trade_price, None);
+
+        // 回调返回 false 时停止
+        let mut count = 0;
+        query(root, &Filter::default(), |_| {
+            count += 1;
+            count < 3
+        })
+        .unwrap();
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn flag_duplicate_trace_id() {
+        let root = std::env::temp_dir().join(format!("reader_dedup_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let options = Options {
+            root: root.to_str().unwrap().to_string(),
+            store: StoreKind::Noop,
+            dedup: DedupMode::Flag,
+            ..Options::default()
+        };
+        let db = Db::init(options.clone()).unwrap();
+        let coin = CString::new("USDT").unwrap();
+        let amount = CString::new("1").unwrap();
+        let trace_id = CString::new("e1").unwrap();
+        let event =
+            EventV2::balance_change(1, 2, coin.as_ptr(), amount.as_ptr(), trace_id.as_ptr(), 0);
+        db.write_v2(&event).unwrap();
+        db.write_v2(&event).unwrap();
+        assert!(db
+            .exists_trace_id(Some(EVENT_BALANCE_CHANGE), "e1")
+            .unwrap());
+        // 文件还没写完, 只能从索引里查到
+        assert!(db.exists_trace_id(None, "e1").unwrap());
+        db.close_db().unwrap();
+
+        let mut rows = Vec::new();
+        db.query(&Filter::default(), |row| {
+            rows.push(row.duplicate);
+            true
+        })
+        .unwrap();
+        assert_eq!(rows, vec![false, true]);
+        assert!(db.exists_trace_id(None, "e1").unwrap());
+        assert!(!db.exists_trace_id(None, "e2").unwrap());
+
+        // 重启后索引还在, drop 模式直接拒绝
+        let db = Db::init(Options {
+            dedup: DedupMode::Drop,
+            ..options
+        })
+        .unwrap();
+        let ret = db.write_v2(&event);
+        assert!(matches!(ret, Err(crate::error::Error::Duplicate(_))));
+        db.close_db().unwrap();
+    }
+
+    #[test]
+    fn exists_trace_id_from_files() {
+        let root = std::env::temp_dir().join(format!("reader_exists_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        // 不开去重就没有索引, 只能从文件的 bloom filter 查
+        let db = Db::init(Options {
+            root: root.to_str().unwrap().to_string(),
+            store: StoreKind::Noop,
+            writer: WriterOptions {
+                max_row_group_size: 4,
+                ..WriterOptions::default()
+            },
+            ..Options::default()
+        })
+        .unwrap();
+        let coin = CString::new("USDT").unwrap();
+        let amount = CString::new("1").unwrap();
+        for i in 0..20u64 {
+            let trace_id = CString::new(format!("t{}", i)).unwrap();
+            let event =
+                EventV2::balance_change(i, 2, coin.as_ptr(), amount.as_ptr(), trace_id.as_ptr(), 0);
+            db.write_v2(&event).unwrap();
+        }
+        db.close_db().unwrap();
+
+        assert!(db.exists_trace_id(None, "t13").unwrap());
+        assert!(db
+            .exists_trace_id(Some(EVENT_BALANCE_CHANGE), "t13")
+            .unwrap());
+        assert!(!db.exists_trace_id(Some(EVENT_TRADE), "t13").unwrap());
+        assert!(!db.exists_trace_id(None, "t99").unwrap());
+
+        // t13 在第 4 个 row group, bloom filter 不能把它排除掉, 不存在的 t99 全部排除
+        let file_name = fs::read_dir(&root)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "parquet"))
+            .unwrap();
+        let file_name = file_name.to_str().unwrap();
+        let reader = SerializedFileReader::new(File::open(file_name).unwrap()).unwrap();
+        let row_groups = (0..reader.num_row_groups()).collect::<Vec<_>>();
+        assert_eq!(row_groups.len(), 5);
+        let column = reader
+            .metadata()
+            .file_metadata()
+            .schema_descr()
+            .columns()
+            .iter()
+            .position(|c| c.name() == TRACE_ID_COLUMN)
+            .unwrap();
+        let matched = bloom_filter_match(file_name, row_groups.clone(), column, "t13").unwrap();
+        assert!(matched.contains(&3));
+        assert!(bloom_filter_match(file_name, row_groups, column, "t99")
+            .unwrap()
+            .is_empty());
+    }
+}
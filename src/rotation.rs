@@ -0,0 +1,214 @@
+use chrono::{DateTime, TimeZone, Utc};
+use std::time::Duration;
+
+use super::error::{Error, Result};
+use super::zone::Zone;
+
+/// 文件滚动策略: 时间窗口, 文件大小, 行数, 哪个先达到就先滚动。
+/// 同一个窗口里滚动出来的文件靠文件名的序号区分, 例如 20230316_17_1 20230316_17_2
+#[derive(Debug, Clone, PartialEq)]
+pub struct RotationPolicy {
+    // 对齐到 zone 的窗口, 单位秒
+    pub window_secs: u64,
+    pub zone: Zone,
+    // 按 wal 编码估算的未压缩大小, 实际的 parquet 文件会更小
+    pub max_bytes: Option<u64>,
+    pub max_rows: Option<u64>,
+    // 窗口结束后再等多久由后台线程 seal, 给窗口边界上还在提交的写入留一点时间
+    pub seal_delay_secs: u64,
+    // 超过这么久没有写入就 seal, None 表示不按空闲 seal
+    pub max_idle_secs: Option<u64>,
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        RotationPolicy {
+            window_secs: 3600,
+            zone: Zone::Utc,
+            max_bytes: None,
+            max_rows: None,
+            seal_delay_secs: 5,
+            max_idle_secs: None,
+        }
+    }
+}
+
+/// 当前文件已经写入的数据量
+#[derive(Debug, Default, Clone)]
+pub struct FileStats {
+    pub rows: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    Window,
+    Size,
+    Rows,
+    Idle,
+}
+
+impl RotationPolicy {
+    /// 支持 1m 5m 15m 1h 1d 这样的写法
+    pub fn parse_window(value: &str) -> Result<u64> {
+        let invalid =
+            || Error::InvalidArgument(format!("rotation window {} is not support", value));
+        // 文件名最细到分钟, 不支持秒
+        let unit = match value.chars().last() {
+            Some('m') => 60,
+            Some('h') => 3600,
+            Some('d') => 86400,
+            _ => return Err(invalid()),
+        };
+        let count = value[..value.len() - 1]
+            .parse::<u64>()
+            .map_err(|_| invalid())?;
+        if count == 0 {
+            return Err(invalid());
+        }
+        Ok(count * unit)
+    }
+
+    // 按照窗口对齐到起点, 整小时的周期沿用 %Y%m%d_%H 的格式
+    pub fn window_label(&self, now: DateTime<Utc>) -> String {
+        let interval = self.window_secs.max(1) as i64;
+        let local_secs = now.timestamp() + self.zone.offset_secs(now);
+        let start = local_secs - local_secs.rem_euclid(interval);
+        let start = Utc.timestamp_opt(start, 0).unwrap();
+        if interval % 3600 == 0 {
+            start.format("%Y%m%d_%H").to_string()
+        } else {
+            start.format("%Y%m%d_%H%M").to_string()
+        }
+    }
+
+    /// now 所在窗口的结束时间, epoch 秒
+    pub fn window_end(&self, now: DateTime<Utc>) -> i64 {
+        let interval = self.window_secs.max(1) as i64;
+        let offset = self.zone.offset_secs(now);
+        let local_secs = now.timestamp() + offset;
+        local_secs - local_secs.rem_euclid(interval) + interval - offset
+    }
+
+    /// 后台线程定时检查当前文件, 窗口结束超过 seal_delay_secs 或者空闲太久就 seal
+    pub fn should_seal(&self, window_end: i64, idle: Duration, now: i64) -> Option<Reason> {
+        if now >= window_end + self.seal_delay_secs as i64 {
+            return Some(Reason::Window);
+        }
+        if matches!(self.max_idle_secs, Some(max) if idle >= Duration::from_secs(max)) {
+            return Some(Reason::Idle);
+        }
+        None
+    }
+
+    /// 写入 rows 行 bytes 字节之前检查是否需要换新文件,
+    /// 空文件不会因为大小或行数滚动, 单批超过限制时整批写进同一个文件
+    pub fn should_rotate(
+        &self,
+        file_label: &str,
+        now_label: &str,
+        stats: &FileStats,
+        rows: u64,
+        bytes: u64,
+    ) -> Option<Reason> {
+        if file_label != now_label {
+            return Some(Reason::Window);
+        }
+        if stats.rows == 0 {
+            return None;
+        }
+        if matches!(self.max_rows, Some(max) if stats.rows + rows > max) {
+            return Some(Reason::Rows);
+        }
+        if matches!(self.max_bytes, Some(max) if stats.bytes + bytes > max) {
+            return Some(Reason::Size);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_window() {
+        assert_eq!(RotationPolicy::parse_window("1m").unwrap(), 60);
+        assert_eq!(RotationPolicy::parse_window("15m").unwrap(), 900);
+        assert_eq!(RotationPolicy::parse_window("1h").unwrap(), 3600);
+        assert_eq!(RotationPolicy::parse_window("1d").unwrap(), 86400);
+        assert!(RotationPolicy::parse_window("0m").is_err());
+        assert!(RotationPolicy::parse_window("1w").is_err());
+        assert!(RotationPolicy::parse_window("30s").is_err());
+        assert!(RotationPolicy::parse_window("h").is_err());
+    }
+
+    #[test]
+    fn rotate_on_first_limit() {
+        let policy = RotationPolicy {
+            window_secs: 300,
+            max_bytes: Some(1000),
+            max_rows: Some(10),
+            ..RotationPolicy::default()
+        };
+        let stats = FileStats {
+            rows: 8,
+            bytes: 900,
+        };
+        let label = "20230316_1705";
+        assert_eq!(policy.should_rotate(label, label, &stats, 2, 100), None);
+        assert_eq!(
+            policy.should_rotate(label, label, &stats, 3, 10),
+            Some(Reason::Rows)
+        );
+        assert_eq!(
+            policy.should_rotate(label, label, &stats, 1, 101),
+            Some(Reason::Size)
+        );
+        assert_eq!(
+            policy.should_rotate(label, "20230316_1710", &stats, 1, 1),
+            Some(Reason::Window)
+        );
+        // 空文件不按大小滚动
+        let empty = FileStats::default();
+        assert_eq!(policy.should_rotate(label, label, &empty, 100, 5000), None);
+    }
+
+    #[test]
+    fn window_label_aligns_to_window() {
+        let policy = RotationPolicy {
+            window_secs: 900,
+            ..RotationPolicy::default()
+        };
+        let now = Utc.with_ymd_and_hms(2023, 3, 16, 17, 29, 59).unwrap();
+        assert_eq!(policy.window_label(now), "20230316_1715");
+        let end = Utc.with_ymd_and_hms(2023, 3, 16, 17, 30, 0).unwrap();
+        assert_eq!(policy.window_end(now), end.timestamp());
+
+        // 按上海时间切天, 17:29 UTC 已经是第二天
+        let daily = RotationPolicy {
+            window_secs: 86400,
+            zone: Zone::parse("Asia/Shanghai").unwrap(),
+            ..RotationPolicy::default()
+        };
+        assert_eq!(daily.window_label(now), "20230317_00");
+        let end = Utc.with_ymd_and_hms(2023, 3, 17, 16, 0, 0).unwrap();
+        assert_eq!(daily.window_end(now), end.timestamp());
+    }
+
+    #[test]
+    fn seal_expired_or_idle_file() {
+        let policy = RotationPolicy {
+            seal_delay_secs: 5,
+            max_idle_secs: Some(60),
+            ..RotationPolicy::default()
+        };
+        let idle = Duration::from_secs(1);
+        assert_eq!(policy.should_seal(1000, idle, 1004), None);
+        assert_eq!(policy.should_seal(1000, idle, 1005), Some(Reason::Window));
+        assert_eq!(
+            policy.should_seal(1000, Duration::from_secs(60), 900),
+            Some(Reason::Idle)
+        );
+    }
+}
@@ -1,78 +1,600 @@
-use anyhow::Result;
-use chrono::Local;
 use s3::bucket::Bucket;
 use s3::creds::Credentials;
-use s3::error::S3Error;
-
-use log::{debug, error, info, log_enabled, Level};
-
-pub struct Uploader {}
-
-impl Uploader {
-    pub fn upload_retry(&self, local_file: &str) -> anyhow::Result<()> {
-        // return Ok(())
-        info!("upload s3 file {}", local_file);
-        for _ in 0..100 {
-            // 300s => 5minutes
-            match self.upload(local_file) {
-                Ok(_) => return Ok(()),
-                Err(e) => {
-                    error!("upload s3 failed {:?}", e);
-                    std::thread::sleep(std::time::Duration::from_secs(3));
-                }
-            }
-        }
-        return anyhow::bail!("upload failed");
-    }
+use s3::region::Region;
+use s3::serde_types::Part;
 
-    pub fn upload(&self, local_file: &str) -> anyhow::Result<()> {
-        let start = Local::now().timestamp_millis();
+use log::{error, info};
 
-        let file_name = local_file.split("/").last().unwrap();
-        // 20221012_14_01.parquet 这种格式
-        let names = file_name.split("_").collect::<Vec<_>>();
-        let s3_key = if names.len() == 3 {
-            let mut s3_key = names.join("/");
-            let extend = format!("_{}", Local::now().timestamp_millis());
-            s3_key.extend(extend.chars());
-            format!("experience_coupon/{}", s3_key.as_str())
-        } else {
-            // let c = format!("file_name is not support {:?}", local_file);
-            panic!("file_name is not support");
-        };
+use super::config::Options;
+use super::store::{Checksum, ObjectStore};
+
+/// 上传到 s3 或者兼容 s3 的服务 (minio, GCS interoperability 等)
+pub struct S3Store {
+    bucket: Option<String>,
+    region: Option<String>,
+    endpoint: Option<String>,
+    // 超过这个大小走 multipart upload
+    multipart_threshold: u64,
+    part_size: u64,
+    concurrency: usize,
+    // 比较 ETag 和本地 md5, 使用 SSE-KMS 的 bucket ETag 不是 md5, 需要关掉
+    verify_etag: bool,
+    // rust-s3 的 http client 依赖 tokio, 需要在 tokio runtime 里执行
+    runtime: tokio::runtime::Runtime,
+}
 
-        info!("start to upload file_name {}", s3_key.as_str());
-        let name = std::env::var("s3.bucketname")?;
-        let region = std::env::var("AWS_REGION")?;
+impl S3Store {
+    pub fn new(options: &Options) -> std::io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        Ok(S3Store {
+            bucket: options.bucket.clone(),
+            region: options.region.clone(),
+            endpoint: options.endpoint.clone(),
+            multipart_threshold: options.multipart_threshold_bytes,
+            part_size: options.multipart_part_size_bytes,
+            concurrency: options.upload_concurrency.max(1),
+            verify_etag: options.verify_etag,
+            runtime,
+        })
+    }
+
+    fn bucket(&self) -> anyhow::Result<Bucket> {
+        let name = match &self.bucket {
+            Some(name) => name.clone(),
+            None => std::env::var("s3.bucketname")?,
+        };
+        let region = match &self.region {
+            Some(region) => region.clone(),
+            None => std::env::var("AWS_REGION")?,
+        };
+        let region = match &self.endpoint {
+            // 自定义 endpoint 用于 minio 之类兼容 s3 的服务
+            Some(endpoint) => Region::Custom {
+                region,
+                endpoint: endpoint.clone(),
+            },
+            None => region.as_str().parse()?,
+        };
         let bucket = Bucket::new(
             name.as_str(),
-            region.as_str().parse()?,
+            region,
             // Credentials are collected from environment, config, profile or instance metadata
             Credentials::default()?,
         )?;
+        Ok(if self.endpoint.is_some() {
+            bucket.with_path_style()
+        } else {
+            bucket
+        })
+    }
 
-        let s3_path = s3_key.as_str();
-        let content = std::fs::read(s3_path)?;
-        use futures::executor;
+    fn put_single(
+        &self,
+        mut bucket: Bucket,
+        local_file: &str,
+        s3_path: &str,
+        checksum: &Checksum,
+    ) -> anyhow::Result<String> {
+        let content = std::fs::read(local_file)?;
+        // s3 收到的数据和 Content-MD5 / x-amz-checksum-crc32c 不一致时会直接拒绝
+        bucket.add_header("Content-MD5", base64::encode(checksum.md5.0).as_str());
+        bucket.add_header(
+            "x-amz-checksum-crc32c",
+            base64::encode(checksum.crc32c.to_be_bytes()).as_str(),
+        );
 
         let response_data = bucket.put_object(s3_path, content.as_slice());
-        let response_data = executor::block_on(response_data)?;
+        let response_data = self.runtime.block_on(response_data)?;
         if response_data.status_code() != 200 {
             let data = String::from_utf8_lossy(response_data.bytes());
             error!(
                 "start to upload file_name {} status_code == {} response {}",
-                s3_key.as_str(),
+                s3_path,
                 response_data.status_code(),
                 data
             );
             anyhow::bail!("status_code is != 200");
         }
-        let end = Local::now().timestamp_millis();
-        info!(
-            " upload file_name {} cost {} ms",
-            s3_key.as_str(),
-            end - start
+        let etag = response_data
+            .headers()
+            .get("etag")
+            .cloned()
+            .unwrap_or_default();
+        self.check_etag(s3_path, etag.as_str(), checksum.md5_hex().as_str())?;
+        Ok(etag)
+    }
+
+    fn check_etag(&self, s3_path: &str, etag: &str, expected: &str) -> anyhow::Result<()> {
+        if self.verify_etag && etag.trim_matches('"') != expected {
+            anyhow::bail!(
+                "{} etag {} does not match local checksum {}",
+                s3_path,
+                etag,
+                expected
+            );
+        }
+        Ok(())
+    }
+
+    // 按 part_size 切分文件并发上传, 已经完成的 part 记在 <local_file>.parts 里,
+    // 失败重试时跳过这些 part, 全部完成后删除
+    fn upload_multipart(
+        &self,
+        bucket: &Bucket,
+        meta_bucket: &Bucket,
+        local_file: &str,
+        s3_path: &str,
+        size: u64,
+    ) -> anyhow::Result<String> {
+        use futures::stream::{self, StreamExt};
+
+        let parts_file = format!("{}.parts", local_file);
+        let mut progress = match PartProgress::load(parts_file.as_str())? {
+            Some(progress) => {
+                info!(
+                    "resume multipart upload {} upload_id {} with {} parts done",
+                    s3_path,
+                    progress.upload_id.as_str(),
+                    progress.parts.len()
+                );
+                progress
+            }
+            None => {
+                let init = meta_bucket.initiate_multipart_upload(s3_path, CONTENT_TYPE);
+                let init = self.runtime.block_on(init)?;
+                PartProgress::create(parts_file.as_str(), init.upload_id)?
+            }
+        };
+
+        let part_count = size.div_ceil(self.part_size) as u32;
+        let pending = (1..=part_count)
+            .filter(|n| !progress.parts.iter().any(|p| p.part_number == *n))
+            .collect::<Vec<_>>();
+        let upload_id = progress.upload_id.clone();
+        let uploads = stream::iter(pending)
+            .map(|part_number| {
+                let upload_id = upload_id.as_str();
+                async move {
+                    let chunk = read_part(local_file, part_number, self.part_size)?;
+                    let md5 = md5::compute(chunk.as_slice());
+                    let mut bucket = bucket.clone();
+                    bucket.add_header("Content-MD5", base64::encode(md5.0).as_str());
+                    let part = bucket
+                        .put_multipart_chunk(chunk, s3_path, part_number, upload_id, CONTENT_TYPE)
+                        .await?;
+                    self.check_etag(s3_path, part.etag.as_str(), format!("{:x}", md5).as_str())?;
+                    anyhow::Ok(part)
+                }
+            })
+            .buffer_unordered(self.concurrency);
+
+        let failed = self.runtime.block_on(async {
+            let mut uploads = Box::pin(uploads);
+            let mut failed = None;
+            while let Some(part) = uploads.next().await {
+                match part.and_then(|part| progress.record(part)) {
+                    Ok(()) => {}
+                    Err(e) => {
+                        error!("upload part of {} failed {:?}", s3_path, e);
+                        failed = Some(e);
+                    }
+                }
+            }
+            failed
+        });
+        if let Some(e) = failed {
+            if e.to_string().contains("NoSuchUpload") {
+                // upload_id 已经失效, 下次从头开始
+                let _ = std::fs::remove_file(parts_file.as_str());
+            }
+            return Err(e);
+        }
+
+        let mut parts = std::mem::take(&mut progress.parts);
+        parts.sort_by_key(|p| p.part_number);
+        let expected = multipart_etag(&parts);
+        let complete = bucket.complete_multipart_upload(s3_path, upload_id.as_str(), parts);
+        let response_data = self.runtime.block_on(complete)?;
+        if response_data.status_code() != 200 {
+            anyhow::bail!(
+                "complete multipart upload status_code == {}",
+                response_data.status_code()
+            );
+        }
+        let _ = std::fs::remove_file(parts_file.as_str());
+        let etag = xml_value(
+            String::from_utf8_lossy(response_data.bytes()).as_ref(),
+            "ETag",
+        )
+        .unwrap_or_default();
+        self.check_etag(s3_path, etag.as_str(), expected.as_str())?;
+        Ok(etag)
+    }
+}
+
+// multipart 对象的 ETag 是各个 part md5 拼接后再算 md5, 后面加上 -part 数量
+fn multipart_etag(parts: &[Part]) -> String {
+    let mut md5 = md5::Context::new();
+    for part in parts {
+        let hex = part.etag.trim_matches('"');
+        let digest = (0..hex.len() / 2)
+            .filter_map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok())
+            .collect::<Vec<_>>();
+        md5.consume(digest);
+    }
+    format!("{:x}-{}", md5.compute(), parts.len())
+}
+
+impl ObjectStore for S3Store {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    fn put(&self, local_file: &str, key: &str, checksum: &Checksum) -> anyhow::Result<String> {
+        let bucket = self.bucket()?;
+        // 校验和同时写到对象的 metadata 里, 方便审计
+        let mut meta_bucket = bucket.clone();
+        meta_bucket.add_header("x-amz-meta-md5", checksum.md5_hex().as_str());
+        meta_bucket.add_header("x-amz-meta-crc32c", checksum.crc32c_hex().as_str());
+
+        let size = std::fs::metadata(local_file)?.len();
+        if size > self.multipart_threshold {
+            self.upload_multipart(&bucket, &meta_bucket, local_file, key, size)
+        } else {
+            self.put_single(meta_bucket, local_file, key, checksum)
+        }
+    }
+}
+
+const CONTENT_TYPE: &str = "application/octet-stream";
+
+fn read_part(local_file: &str, part_number: u32, part_size: u64) -> anyhow::Result<Vec<u8>> {
+    use std::io::{Read, Seek, SeekFrom};
+    let mut file = std::fs::File::open(local_file)?;
+    file.seek(SeekFrom::Start((part_number as u64 - 1) * part_size))?;
+    let mut chunk = Vec::with_capacity(part_size as usize);
+    file.take(part_size).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+// 从 s3 返回的 xml 里取一个字段, 只用于 CompleteMultipartUploadResult 这种简单结构
+fn xml_value(xml: &str, tag: &str) -> Option<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let start = xml.find(open.as_str())? + open.len();
+    let end = start + xml[start..].find(close.as_str())?;
+    Some(
+        xml[start..end]
+            .replace("&quot;", "\"")
+            .replace("&#34;", "\""),
+    )
+}
+
+/// multipart upload 的进度文件, 第一行是 upload_id, 之后每行一个完成的 part: `part_number \t etag`
+struct PartProgress {
+    file: std::fs::File,
+    upload_id: String,
+    parts: Vec<Part>,
+}
+
+impl PartProgress {
+    fn load(path: &str) -> anyhow::Result<Option<Self>> {
+        use std::io::{BufRead, BufReader};
+        if !std::path::Path::new(path).exists() {
+            return Ok(None);
+        }
+        let mut lines = BufReader::new(std::fs::File::open(path)?).lines();
+        let upload_id = match lines.next() {
+            Some(line) => line?,
+            None => return Ok(None),
+        };
+        let mut parts = Vec::new();
+        for line in lines {
+            let line = line?;
+            // 崩溃时最后一行可能不完整, 这个 part 重新上传
+            if let Some((number, etag)) = line.split_once('\t') {
+                if let Ok(part_number) = number.parse() {
+                    parts.push(Part {
+                        part_number,
+                        etag: etag.to_string(),
+                    });
+                }
+            }
+        }
+        let file = std::fs::OpenOptions::new().append(true).open(path)?;
+        Ok(Some(PartProgress {
+            file,
+            upload_id,
+            parts,
+        }))
+    }
+
+    fn create(path: &str, upload_id: String) -> anyhow::Result<Self> {
+        use std::io::Write;
+        let mut file = std::fs::File::create(path)?;
+        writeln!(file, "{}", upload_id.as_str())?;
+        file.sync_data()?;
+        Ok(PartProgress {
+            file,
+            upload_id,
+            parts: Vec::new(),
+        })
+    }
+
+    fn record(&mut self, part: Part) -> anyhow::Result<()> {
+        use std::io::Write;
+        writeln!(self.file, "{}\t{}", part.part_number, part.etag.as_str())?;
+        self.file.sync_data()?;
+        self.parts.push(part);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+
+    struct Request {
+        method: String,
+        path: String,
+        headers: HashMap<String, String>,
+        body: Vec<u8>,
+    }
+
+    // 进程内的 s3 替身, 记录收到的请求并按 status 返回, ETag 和 s3 一样按 md5 计算
+    struct MockS3 {
+        endpoint: String,
+        requests: Arc<Mutex<Vec<Request>>>,
+    }
+
+    impl MockS3 {
+        fn start(status: u16) -> Self {
+            MockS3::start_with(status, false)
+        }
+
+        // corrupt = true 时返回和收到的数据不一致的 ETag, 模拟传输中损坏
+        fn start_with(status: u16, corrupt: bool) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let endpoint = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let recorded = requests.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(_) => return,
+                    };
+                    let recorded = recorded.clone();
+                    std::thread::spawn(move || serve(stream, status, corrupt, recorded));
+                }
+            });
+            MockS3 { endpoint, requests }
+        }
+    }
+
+    fn serve(
+        mut stream: TcpStream,
+        status: u16,
+        corrupt: bool,
+        recorded: Arc<Mutex<Vec<Request>>>,
+    ) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            let mut parts = line.split_whitespace();
+            let method = parts.next().unwrap_or_default().to_string();
+            let path = parts.next().unwrap_or_default().to_string();
+
+            let mut headers = HashMap::new();
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                let header = header.trim_end();
+                if header.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+                }
+            }
+            let len = headers
+                .get("content-length")
+                .and_then(|len| len.parse::<usize>().ok())
+                .unwrap_or(0);
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).unwrap();
+
+            let mut etag = if corrupt {
+                format!("{:x}", md5::compute(b"corrupted"))
+            } else {
+                format!("{:x}", md5::compute(body.as_slice()))
+            };
+            let (reason, content) = if status != 200 {
+                (
+                    "Internal Server Error",
+                    "<Error><Code>InternalError</Code></Error>".to_string(),
+                )
+            } else if method == "POST" && path.contains("?uploads") {
+                (
+                    "OK",
+                    "<InitiateMultipartUploadResult><Bucket>test-bucket</Bucket>\
+                     <Key>key</Key><UploadId>mock-upload</UploadId>\
+                     </InitiateMultipartUploadResult>"
+                        .to_string(),
+                )
+            } else if method == "POST" && path.contains("uploadId=") {
+                let xml = String::from_utf8_lossy(body.as_slice()).replace("&quot;", "\"");
+                let parts = xml
+                    .split("<ETag>")
+                    .skip(1)
+                    .map(|rest| Part {
+                        part_number: 0,
+                        etag: rest.split("</ETag>").next().unwrap().to_string(),
+                    })
+                    .collect::<Vec<_>>();
+                etag = multipart_etag(&parts);
+                (
+                    "OK",
+                    format!(
+                        "<CompleteMultipartUploadResult><ETag>&quot;{}&quot;</ETag>\
+                         </CompleteMultipartUploadResult>",
+                        etag
+                    ),
+                )
+            } else {
+                ("OK", String::new())
+            };
+            let response = format!(
+                "HTTP/1.1 {} {}\r\nETag: \"{}\"\r\nContent-Length: {}\r\n\r\n{}",
+                status,
+                reason,
+                etag,
+                content.len(),
+                content
+            );
+            recorded.lock().push(Request {
+                method,
+                path,
+                headers,
+                body,
+            });
+            if stream.write_all(response.as_bytes()).is_err() {
+                return;
+            }
+        }
+    }
+
+    const KEY: &str = "experience_coupon/20230316/17/1.parquet";
+
+    fn store(endpoint: &str) -> S3Store {
+        std::env::set_var("AWS_ACCESS_KEY_ID", "test");
+        std::env::set_var("AWS_SECRET_ACCESS_KEY", "test");
+        S3Store::new(&Options {
+            bucket: Some("test-bucket".to_string()),
+            region: Some("us-east-1".to_string()),
+            endpoint: Some(endpoint.to_string()),
+            ..Options::default()
+        })
+        .unwrap()
+    }
+
+    fn local_file(dir: &str, content: &[u8]) -> String {
+        let root = std::env::temp_dir().join(format!("{}_{}", dir, std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let name = root.join("20230316_17_1.parquet");
+        std::fs::write(&name, content).unwrap();
+        name.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn put_sends_local_file_under_key() {
+        let mock = MockS3::start(200);
+        let content = b"PAR1 local content PAR1";
+        let file = local_file("s3_upload_ok", content);
+        let checksum = Checksum::of_file(file.as_str()).unwrap();
+
+        let etag = store(mock.endpoint.as_str())
+            .put(file.as_str(), KEY, &checksum)
+            .unwrap();
+        let md5 = format!("{:x}", md5::compute(content));
+        assert_eq!(etag, format!("\"{}\"", md5));
+        assert_eq!(
+            checksum.crc32c_hex(),
+            format!("{:08x}", crc32c::crc32c(content))
+        );
+
+        let requests = mock.requests.lock();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "PUT");
+        assert_eq!(
+            requests[0].path,
+            "/test-bucket/experience_coupon/20230316/17/1.parquet"
+        );
+        assert_eq!(requests[0].body, content);
+        assert_eq!(
+            requests[0].headers.get("content-md5"),
+            Some(&base64::encode(md5::compute(content).0))
         );
-        return Ok(());
+        assert_eq!(requests[0].headers.get("x-amz-meta-md5"), Some(&md5));
+    }
+
+    #[test]
+    fn put_fails_on_error_status() {
+        let mock = MockS3::start(500);
+        let file = local_file("s3_upload_err", b"PAR1");
+        let checksum = Checksum::of_file(file.as_str()).unwrap();
+
+        assert!(store(mock.endpoint.as_str())
+            .put(file.as_str(), KEY, &checksum)
+            .is_err());
+        assert_eq!(mock.requests.lock().len(), 1);
+    }
+
+    #[test]
+    fn put_fails_on_etag_mismatch() {
+        let mock = MockS3::start_with(200, true);
+        let file = local_file("s3_upload_corrupt", b"PAR1");
+        let checksum = Checksum::of_file(file.as_str()).unwrap();
+
+        let err = store(mock.endpoint.as_str())
+            .put(file.as_str(), KEY, &checksum)
+            .unwrap_err();
+        assert!(err.to_string().contains("does not match"));
+    }
+
+    #[test]
+    fn multipart_upload_resumes_from_completed_parts() {
+        let mock = MockS3::start(200);
+        let content = (0..10u8).collect::<Vec<_>>();
+        let file = local_file("s3_upload_multipart", content.as_slice());
+        // 模拟上一次上传完成了第 1 个 part
+        std::fs::write(
+            format!("{}.parts", file),
+            format!("mock-upload\n1\t\"{:x}\"\n", md5::compute(&content[..4])),
+        )
+        .unwrap();
+
+        let mut s3 = store(mock.endpoint.as_str());
+        s3.multipart_threshold = 4;
+        s3.part_size = 4;
+        let checksum = Checksum::of_file(file.as_str()).unwrap();
+        let etag = s3.put(file.as_str(), KEY, &checksum).unwrap();
+        assert!(etag.ends_with("-3\""));
+        assert!(!std::path::Path::new(format!("{}.parts", file).as_str()).exists());
+
+        let requests = mock.requests.lock();
+        let mut parts = requests
+            .iter()
+            .filter(|r| r.method == "PUT")
+            .map(|r| (r.path.clone(), r.body.clone()))
+            .collect::<Vec<_>>();
+        parts.sort();
+        assert_eq!(parts.len(), 2);
+        assert!(parts[0].0.contains("partNumber=2"));
+        assert_eq!(parts[0].1, vec![4, 5, 6, 7]);
+        assert!(parts[1].0.contains("partNumber=3"));
+        assert_eq!(parts[1].1, vec![8, 9]);
+        // 每个 part 都带上自己的 Content-MD5
+        for request in requests.iter().filter(|r| r.method == "PUT") {
+            assert_eq!(
+                request.headers.get("content-md5"),
+                Some(&base64::encode(md5::compute(request.body.as_slice()).0))
+            );
+        }
+        // 续传不会重新 initiate
+        assert!(!requests.iter().any(|r| r.path.contains("?uploads")));
+        assert!(requests
+            .iter()
+            .any(|r| r.method == "POST" && r.path.contains("uploadId=mock-upload")));
     }
 }
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use arrow::datatypes::{DataType, Field, Schema, TimeUnit};
+use parquet::file::metadata::KeyValue;
+
+use super::decimal::AmountSpec;
+
+/// 写入 parquet key-value metadata 的 schema 版本号
+pub const SCHEMA_VERSION_KEY: &str = "event.schema.version";
+
+/// 新文件使用的 schema 版本
+pub const CURRENT_VERSION: u32 = 5;
+
+// 列的类型, amount 类的列需要根据配置生成 Decimal128
+#[derive(Clone, Copy)]
+enum ColumnType {
+    Boolean,
+    UInt8,
+    UInt64,
+    Utf8,
+    Amount,
+    Timestamp,
+}
+
+struct Column {
+    name: &'static str,
+    // 从哪个版本开始有这一列
+    since: u32,
+    nullable: bool,
+    data_type: ColumnType,
+    // (版本, 类型): 早于这个版本的文件里这一列是另一个类型
+    before: Option<(u32, ColumnType)>,
+}
+
+/// 所有版本的列, 只能在末尾追加, 新增的列必须是 nullable,
+/// 这样旧版本的文件可以直接按新 schema 读出 (缺少的列为 null)。
+/// 唯一的例外是 amount, 版本 1 的文件里是十进制字符串, 读的时候按字符串处理
+const COLUMNS: &[Column] = &[
+    Column {
+        name: "event_type",
+        since: 1,
+        nullable: false,
+        data_type: ColumnType::UInt8,
+        before: None,
+    },
+    Column {
+        name: "account_id",
+        since: 1,
+        nullable: false,
+        data_type: ColumnType::UInt64,
+        before: None,
+    },
+    Column {
+        name: "strategy_id",
+        since: 1,
+        nullable: false,
+        data_type: ColumnType::UInt64,
+        before: None,
+    },
+    Column {
+        name: "coin",
+        since: 1,
+        nullable: false,
+        data_type: ColumnType::Utf8,
+        before: None,
+    },
+    Column {
+        name: "amount",
+        since: 1,
+        nullable: false,
+        data_type: ColumnType::Amount,
+        before: Some((2, ColumnType::Utf8)),
+    },
+    Column {
+        name: "trace_id",
+        since: 1,
+        nullable: false,
+        data_type: ColumnType::Utf8,
+        before: None,
+    },
+    Column {
+        name: "fee_coin",
+        since: 2,
+        nullable: true,
+        data_type: ColumnType::Utf8,
+        before: None,
+    },
+    Column {
+        name: "fee_amount",
+        since: 2,
+        nullable: true,
+        data_type: ColumnType::Amount,
+        before: None,
+    },
+    // 调用方传入的事件时间, 旧的调用方没有
+    Column {
+        name: "event_time",
+        since: 3,
+        nullable: true,
+        data_type: ColumnType::Timestamp,
+        before: None,
+    },
+    // 写入 db 的时间, 新文件里总是有值
+    Column {
+        name: "ingest_time",
+        since: 3,
+        nullable: true,
+        data_type: ColumnType::Timestamp,
+        before: None,
+    },
+    // 各类事件自己的数据, 由 event_type 区分, 其他类型的行为 null
+    Column {
+        name: "trade_price",
+        since: 4,
+        nullable: true,
+        data_type: ColumnType::Amount,
+        before: None,
+    },
+    Column {
+        name: "trade_qty",
+        since: 4,
+        nullable: true,
+        data_type: ColumnType::Amount,
+        before: None,
+    },
+    // 0 买, 1 卖
+    Column {
+        name: "trade_side",
+        since: 4,
+        nullable: true,
+        data_type: ColumnType::UInt8,
+        before: None,
+    },
+    Column {
+        name: "fee_rate",
+        since: 4,
+        nullable: true,
+        data_type: ColumnType::Amount,
+        before: None,
+    },
+    // 去重模式为 flag 时, 最近写过相同 (event_type, trace_id) 的行为 true
+    Column {
+        name: "duplicate",
+        since: 5,
+        nullable: true,
+        data_type: ColumnType::Boolean,
+        before: None,
+    },
+];
+
+/// 返回指定版本的 schema, 版本不存在时返回 None, timezone 是时间列的时区
+#[allow(dead_code)]
+pub fn schema(version: u32, amount: &AmountSpec, timezone: &str) -> Option<Arc<Schema>> {
+    if version == 0 || version > CURRENT_VERSION {
+        return None;
+    }
+    Some(build(version, amount, timezone))
+}
+
+/// 返回当前版本的 schema, 新写的文件都用这个版本
+pub fn current(amount: &AmountSpec, timezone: &str) -> Arc<Schema> {
+    build(CURRENT_VERSION, amount, timezone)
+}
+
+// 调用方保证 version 在 1..=CURRENT_VERSION 之间
+fn build(version: u32, amount: &AmountSpec, timezone: &str) -> Arc<Schema> {
+    let fields = COLUMNS
+        .iter()
+        .filter(|c| c.since <= version)
+        .map(|c| {
+            let column_type = match c.before {
+                Some((until, column_type)) if version < until => column_type,
+                _ => c.data_type,
+            };
+            let data_type = match column_type {
+                ColumnType::Boolean => DataType::Boolean,
+                ColumnType::UInt8 => DataType::UInt8,
+                ColumnType::UInt64 => DataType::UInt64,
+                ColumnType::Utf8 => DataType::Utf8,
+                ColumnType::Amount => DataType::Decimal128(amount.precision, amount.scale),
+                ColumnType::Timestamp => {
+                    DataType::Timestamp(TimeUnit::Microsecond, Some(timezone.to_string()))
+                }
+            };
+            Field::new(c.name, data_type, c.nullable)
+        })
+        .collect::<Vec<_>>();
+    let metadata = HashMap::from([(SCHEMA_VERSION_KEY.to_string(), version.to_string())]);
+    Arc::new(Schema::new_with_metadata(fields, metadata))
+}
+
+pub fn key_value(version: u32) -> KeyValue {
+    KeyValue::new(SCHEMA_VERSION_KEY.to_string(), version.to_string())
+}
+
+/// 从 parquet 文件的 key-value metadata 里读出版本, 加入版本号之前写的文件都是 1
+#[allow(dead_code)]
+pub fn file_version(key_value_metadata: Option<&Vec<KeyValue>>) -> u32 {
+    key_value_metadata
+        .and_then(|kvs| kvs.iter().find(|kv| kv.key == SCHEMA_VERSION_KEY))
+        .and_then(|kv| kv.value.as_ref())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_versions_only_append_nullable_columns() {
+        let amount = AmountSpec::default();
+        let mut previous = schema(1, &amount, "UTC").unwrap();
+        assert_eq!(previous.fields().len(), 6);
+        assert_eq!(
+            previous.field_with_name("amount").unwrap().data_type(),
+            &DataType::Utf8
+        );
+        for version in 2..=CURRENT_VERSION {
+            let current = schema(version, &amount, "UTC").unwrap();
+            let (old, new) = current.fields().split_at(previous.fields().len());
+            // 只有 amount 在版本 2 改成了 Decimal128
+            let names = |fields: &[Field]| {
+                fields
+                    .iter()
+                    .map(|f| (f.name().clone(), f.is_nullable()))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(names(old), names(previous.fields().as_slice()));
+            if version > 2 {
+                assert_eq!(old, previous.fields().as_slice());
+            }
+            assert!(new.iter().all(|f| f.is_nullable()));
+            previous = current;
+        }
+        assert!(schema(CURRENT_VERSION + 1, &amount, "UTC").is_none());
+        assert_eq!(current(&amount, "UTC"), previous);
+    }
+
+    #[test]
+    fn file_version_defaults_to_one() {
+        assert_eq!(file_version(None), 1);
+        assert_eq!(file_version(Some(&vec![key_value(2)])), 2);
+    }
+}
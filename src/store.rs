@@ -0,0 +1,267 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+use chrono::Local;
+use log::{error, info};
+
+use super::config::{Options, StoreKind};
+use super::key::{self, KeyTemplate};
+use super::metrics::Metrics;
+use super::s3::S3Store;
+
+/// 上传成功后的 key, ETag 以及本地文件的校验和 (hex)
+#[derive(Debug, Clone)]
+pub struct Uploaded {
+    pub key: String,
+    pub etag: String,
+    pub md5: String,
+    pub crc32c: String,
+}
+
+/// 本地文件的校验和, 上传前计算一次
+pub struct Checksum {
+    pub md5: md5::Digest,
+    pub crc32c: u32,
+}
+
+impl Checksum {
+    pub fn of_file(local_file: &str) -> std::io::Result<Self> {
+        use std::io::Read;
+        let mut file = fs::File::open(local_file)?;
+        let mut md5 = md5::Context::new();
+        let mut crc32c = 0;
+        let mut buf = vec![0; 1024 * 1024];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            md5.consume(&buf[..n]);
+            crc32c = crc32c::crc32c_append(crc32c, &buf[..n]);
+        }
+        Ok(Checksum {
+            md5: md5.compute(),
+            crc32c,
+        })
+    }
+
+    pub fn md5_hex(&self) -> String {
+        format!("{:x}", self.md5)
+    }
+
+    pub fn crc32c_hex(&self) -> String {
+        format!("{:08x}", self.crc32c)
+    }
+}
+
+/// 归档 parquet 文件的存储后端, 由配置里的 store 选择。
+/// GCS / Azure 这类提供 s3 兼容接口的服务通过 `S3Store` 加 endpoint 接入。
+pub trait ObjectStore: Send {
+    fn name(&self) -> &'static str;
+
+    /// 把本地文件写到 key, 成功返回存储端的 ETag
+    fn put(&self, local_file: &str, key: &str, checksum: &Checksum) -> anyhow::Result<String>;
+}
+
+pub fn open(options: &Options) -> std::io::Result<Box<dyn ObjectStore>> {
+    Ok(match &options.store {
+        StoreKind::S3 => Box::new(S3Store::new(options)?),
+        StoreKind::Local(dir) => Box::new(LocalStore::new(dir.as_str())),
+        StoreKind::Noop => Box::new(NoopStore {}),
+    })
+}
+
+/// 负责 key 的计算, 校验和以及重试, 具体写入交给 `ObjectStore`
+pub struct Uploader {
+    store: Box<dyn ObjectStore>,
+    key_prefix: String,
+    key_template: KeyTemplate,
+    host: String,
+    metrics: Arc<Metrics>,
+}
+
+impl Uploader {
+    pub fn new(options: &Options) -> std::io::Result<Self> {
+        Ok(Uploader::with_store(open(options)?, options))
+    }
+
+    pub fn with_store(store: Box<dyn ObjectStore>, options: &Options) -> Self {
+        Uploader {
+            store,
+            key_prefix: options.key_prefix.clone(),
+            key_template: options.key_template.clone(),
+            host: key::hostname(),
+            metrics: Arc::new(Metrics::default()),
+        }
+    }
+
+    /// 上传的次数, 失败次数和耗时记录到 db 的 metrics
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn upload_retry(&self, local_file: &str) -> anyhow::Result<Uploaded> {
+        info!("upload {} file {}", self.store.name(), local_file);
+        for _ in 0..100 {
+            // 300s => 5minutes
+            match self.upload_once(local_file) {
+                Ok(uploaded) => return Ok(uploaded),
+                Err(e) => {
+                    error!("upload {} failed {:?}", self.store.name(), e);
+                    std::thread::sleep(std::time::Duration::from_secs(3));
+                }
+            }
+        }
+        anyhow::bail!("upload failed");
+    }
+
+    /// 只尝试一次, 记录到 metrics
+    pub fn upload_once(&self, local_file: &str) -> anyhow::Result<Uploaded> {
+        let start = Instant::now();
+        let ret = self.upload(local_file);
+        self.metrics.record_upload(ret.is_ok(), start.elapsed());
+        ret
+    }
+
+    pub fn upload(&self, local_file: &str) -> anyhow::Result<Uploaded> {
+        let start = Local::now().timestamp_millis();
+
+        let file_name = local_file.rsplit('/').next().unwrap_or(local_file);
+        // key 只由文件名决定, 重传会覆盖同一个对象
+        let key =
+            self.key_template
+                .render(self.key_prefix.as_str(), self.host.as_str(), file_name)?;
+
+        info!("start to upload file_name {}", key.as_str());
+        let checksum = Checksum::of_file(local_file)?;
+        let etag = self.store.put(local_file, key.as_str(), &checksum)?;
+        let end = Local::now().timestamp_millis();
+        info!(" upload file_name {} cost {} ms", key.as_str(), end - start);
+        Ok(Uploaded {
+            key,
+            etag,
+            md5: checksum.md5_hex(),
+            crc32c: checksum.crc32c_hex(),
+        })
+    }
+}
+
+/// 归档到本地 (或者挂载的) 目录, 开发和测试环境使用
+pub struct LocalStore {
+    dir: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(dir: &str) -> Self {
+        LocalStore {
+            dir: PathBuf::from(dir),
+        }
+    }
+}
+
+impl ObjectStore for LocalStore {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn put(&self, local_file: &str, key: &str, checksum: &Checksum) -> anyhow::Result<String> {
+        let target = self.dir.join(key);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // 先写临时文件再 rename, 目录里不会出现写了一半的文件
+        let tmp = target.with_extension("tmp");
+        fs::copy(local_file, &tmp)?;
+        fs::File::open(&tmp)?.sync_all()?;
+        fs::rename(&tmp, &target)?;
+        Ok(checksum.md5_hex())
+    }
+}
+
+/// 不做任何归档, 只保留本地文件
+pub struct NoopStore {}
+
+impl ObjectStore for NoopStore {
+    fn name(&self) -> &'static str {
+        "noop"
+    }
+
+    fn put(&self, local_file: &str, key: &str, _checksum: &Checksum) -> anyhow::Result<String> {
+        info!("noop store skip {} => {}", local_file, key);
+        Ok(String::new())
+    }
+}
+
+/// 保存在内存里的存储, 单元测试使用
+#[cfg(test)]
+#[derive(Default, Clone)]
+pub struct MemoryStore {
+    pub objects: std::sync::Arc<parking_lot::Mutex<std::collections::HashMap<String, Vec<u8>>>>,
+}
+
+#[cfg(test)]
+impl ObjectStore for MemoryStore {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn put(&self, local_file: &str, key: &str, checksum: &Checksum) -> anyhow::Result<String> {
+        let content = fs::read(local_file)?;
+        self.objects.lock().insert(key.to_string(), content);
+        Ok(checksum.md5_hex())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_file(dir: &str, content: &[u8]) -> String {
+        let root = std::env::temp_dir().join(format!("{}_{}", dir, std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let name = root.join("20230316_17_1.parquet");
+        fs::write(&name, content).unwrap();
+        name.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn uploader_writes_file_under_key() {
+        let store = MemoryStore::default();
+        let up = Uploader::with_store(Box::new(store.clone()), &Options::default());
+        let file = local_file("store_memory", b"PAR1 content PAR1");
+
+        let uploaded = up.upload(file.as_str()).unwrap();
+        assert_eq!(
+            uploaded.key,
+            format!(
+                "experience_coupon/20230316/17/{}-1.parquet",
+                key::hostname()
+            )
+        );
+        assert_eq!(
+            uploaded.md5,
+            format!("{:x}", md5::compute(b"PAR1 content PAR1"))
+        );
+        assert_eq!(
+            store.objects.lock().get(uploaded.key.as_str()).unwrap(),
+            b"PAR1 content PAR1"
+        );
+    }
+
+    #[test]
+    fn local_store_copies_file() {
+        let file = local_file("store_local_src", b"PAR1");
+        let dir = std::env::temp_dir().join(format!("store_local_dst_{}", std::process::id()));
+        let up = Uploader::with_store(
+            Box::new(LocalStore::new(dir.to_str().unwrap())),
+            &Options::default(),
+        );
+
+        let uploaded = up.upload(file.as_str()).unwrap();
+        assert_eq!(fs::read(dir.join(uploaded.key)).unwrap(), b"PAR1");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
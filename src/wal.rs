@@ -0,0 +1,397 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::db::Record;
+use super::error::{Error, Result};
+use super::event::EventKind;
+use log::{error, info, warn};
+
+const WAL_FILE: &str = "wal.log";
+
+// 每条记录的格式: | len: u32 | crc32: u32 | payload |
+// payload 第一个字节是类型
+const TAG_FILE: u8 = 0;
+const TAG_RECORD: u8 = 1;
+
+/// 追加写的 write-ahead log, 只保存当前打开的 parquet 文件里的数据。
+/// parquet 文件正常 close (写完 footer) 之后 wal 就会被清空。
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+}
+
+/// wal 回放的结果
+pub struct Replay {
+    // wal 对应的 parquet 文件
+    pub file_name: Option<String>,
+    pub records: Vec<Record>,
+    // 校验通过但是解析不了的记录, 之后的数据都没有回放
+    pub corrupt: bool,
+}
+
+impl Wal {
+    pub fn open(root: &str) -> Result<Self> {
+        let path = Path::new(root).join(WAL_FILE);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Wal { path, file })
+    }
+
+    /// 开始一个新的 parquet 文件, 之前的内容都已经落到 parquet 里了
+    pub fn reset(&mut self, file_name: &str) -> Result<()> {
+        self.file.set_len(0)?;
+        let mut buf = Vec::new();
+        encode_frame(&mut buf, TAG_FILE, file_name.as_bytes());
+        self.file.write_all(&buf)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    pub fn clear(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// buf 是 `encode_record` 编码好的数据, 返回之前保证已经 fsync。
+    /// 失败时截断回写入之前的长度, 写了一半的记录会让回放停在这里, 丢掉之后追加的记录
+    pub fn append(&mut self, buf: &[u8]) -> Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let size = self.size()?;
+        let ret = self.file.write_all(buf).and_then(|_| self.file.sync_data());
+        if let Err(e) = ret {
+            if let Err(te) = self.truncate(size) {
+                error!("wal {:?} truncate to {} failed {}", self.path, size, te);
+            }
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    pub fn size(&self) -> Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    /// 丢掉 size 之后追加的数据
+    pub fn truncate(&mut self, size: u64) -> Result<()> {
+        self.file.set_len(size)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// 复制一份 wal 到 dir 下, 回放不了的记录留给人工处理
+    pub fn quarantine(&self, dir: &str) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        let to = Path::new(dir).join(format!(
+            "{}.{}",
+            WAL_FILE,
+            chrono::Utc::now().timestamp_millis()
+        ));
+        warn!("copy wal {:?} to {:?}", self.path, to);
+        std::fs::copy(&self.path, &to)?;
+        Ok(())
+    }
+
+    /// 读出 wal 里的全部记录, 遇到不完整或者校验失败的记录就停止 (崩溃时最后一次写入可能只写了一半),
+    /// 解析不了的记录同样停止, 并设置 corrupt
+    pub fn replay(&self) -> Result<Replay> {
+        let mut data = Vec::new();
+        File::open(&self.path)?.read_to_end(&mut data)?;
+
+        let mut replay = Replay {
+            file_name: None,
+            records: Vec::new(),
+            corrupt: false,
+        };
+        let mut pos = 0;
+        while pos + 8 <= data.len() {
+            let len = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+            let crc = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap());
+            let start = pos + 8;
+            if len == 0 || start + len > data.len() {
+                warn!("wal {:?} truncated at offset {}", self.path, pos);
+                break;
+            }
+            let payload = &data[start..start + len];
+            if crc32fast::hash(payload) != crc {
+                warn!("wal {:?} checksum mismatch at offset {}", self.path, pos);
+                break;
+            }
+            match payload[0] {
+                TAG_FILE => {
+                    replay.file_name = Some(String::from_utf8_lossy(&payload[1..]).into_owned())
+                }
+                TAG_RECORD => match decode_record(&payload[1..]) {
+                    Ok(record) => replay.records.push(record),
+                    Err(e) => {
+                        warn!("wal {:?} bad record at offset {} {}", self.path, pos, e);
+                        replay.corrupt = true;
+                        break;
+                    }
+                },
+                tag => {
+                    warn!("wal {:?} unknown tag {} at offset {}", self.path, tag, pos);
+                    replay.corrupt = true;
+                    break;
+                }
+            }
+            pos = start + len;
+        }
+        info!(
+            "wal {:?} replay {} records of {:?}",
+            self.path,
+            replay.records.len(),
+            replay.file_name
+        );
+        Ok(replay)
+    }
+}
+
+fn encode_frame(buf: &mut Vec<u8>, tag: u8, body: &[u8]) {
+    let mut payload = Vec::with_capacity(body.len() + 1);
+    payload.push(tag);
+    payload.extend_from_slice(body);
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    buf.extend_from_slice(&payload);
+}
+
+pub fn encode_record(buf: &mut Vec<u8>, record: &Record) {
+    let mut body = Vec::with_capacity(
+        17 + 12 + record.coin.len() + record.amount.len() + record.trace_id.len(),
+    );
+    body.push(record.kind.event_type());
+    body.extend_from_slice(&record.account_id.to_le_bytes());
+    body.extend_from_slice(&record.strategy_id.to_le_bytes());
+    for value in [&record.coin, &record.amount, &record.trace_id] {
+        encode_string(&mut body, value);
+    }
+    // 后来加的字段追加在末尾: 1 字节表示是否有值, 之后是数据
+    encode_optional_string(&mut body, record.fee_coin.as_deref());
+    encode_optional_string(&mut body, record.fee_amount.as_deref());
+    encode_optional_i64(&mut body, record.event_time_us);
+    encode_optional_i64(&mut body, Some(record.ingest_time_us));
+    let trade = record.kind.trade();
+    encode_optional_string(&mut body, trade.map(|t| t.price.as_str()));
+    encode_optional_string(&mut body, trade.map(|t| t.qty.as_str()));
+    body.push(trade.map(|t| t.side as u8).unwrap_or(0));
+    encode_optional_string(&mut body, record.kind.fee_rate());
+    body.push(record.duplicate as u8);
+    encode_frame(buf, TAG_RECORD, &body);
+}
+
+fn encode_string(body: &mut Vec<u8>, value: &str) {
+    body.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    body.extend_from_slice(value.as_bytes());
+}
+
+fn encode_optional_string(body: &mut Vec<u8>, value: Option<&str>) {
+    match value {
+        Some(value) => {
+            body.push(1);
+            encode_string(body, value);
+        }
+        None => body.push(0),
+    }
+}
+
+fn encode_optional_i64(body: &mut Vec<u8>, value: Option<i64>) {
+    match value {
+        Some(value) => {
+            body.push(1);
+            body.extend_from_slice(&value.to_le_bytes());
+        }
+        None => body.push(0),
+    }
+}
+
+fn decode_record(body: &[u8]) -> Result<Record> {
+    let mut cursor = Cursor { body, pos: 0 };
+    let event_type = cursor.take(1)?[0];
+    let account_id = u64::from_le_bytes(cursor.take(8)?.try_into().unwrap());
+    let strategy_id = u64::from_le_bytes(cursor.take(8)?.try_into().unwrap());
+    let coin = cursor.string()?;
+    let amount = cursor.string()?;
+    let trace_id = cursor.string()?;
+    // 旧版本写的记录没有下面这些字段
+    let fee_coin = cursor.optional_string()?;
+    let fee_amount = cursor.optional_string()?;
+    let event_time_us = cursor.optional_i64()?;
+    let ingest_time_us = cursor
+        .optional_i64()?
+        .unwrap_or_else(|| chrono::Utc::now().timestamp_micros());
+    let price = cursor.optional_string()?;
+    let qty = cursor.optional_string()?;
+    let side = match cursor.is_end() {
+        true => 0,
+        false => cursor.take(1)?[0],
+    };
+    let fee_rate = cursor.optional_string()?;
+    let duplicate = !cursor.is_end() && cursor.take(1)?[0] != 0;
+    Ok(Record {
+        kind: EventKind::new(event_type, price, qty, side, fee_rate)?,
+        account_id,
+        strategy_id,
+        coin,
+        amount,
+        trace_id,
+        fee_coin,
+        fee_amount,
+        event_time_us,
+        ingest_time_us,
+        duplicate,
+    })
+}
+
+struct Cursor<'a> {
+    body: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.pos + n > self.body.len() {
+            return Err(Error::Io("wal record is truncated".to_string()));
+        }
+        let value = &self.body[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(value)
+    }
+
+    fn is_end(&self) -> bool {
+        self.pos == self.body.len()
+    }
+
+    fn optional_string(&mut self) -> Result<Option<String>> {
+        if self.is_end() || self.take(1)?[0] == 0 {
+            return Ok(None);
+        }
+        self.string().map(Some)
+    }
+
+    fn optional_i64(&mut self) -> Result<Option<i64>> {
+        if self.is_end() || self.take(1)?[0] == 0 {
+            return Ok(None);
+        }
+        Ok(Some(i64::from_le_bytes(self.take(8)?.try_into().unwrap())))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize;
+        let value = self.take(len)?;
+        String::from_utf8(value.to_vec())
+            .map_err(|e| Error::Io(format!("wal record is not valid utf-8: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{Side, Trade};
+
+    fn record(i: u64) -> Record {
+        Record {
+            kind: EventKind::BalanceChange,
+            account_id: i,
+            strategy_id: i * 2,
+            coin: "usdt".to_string(),
+            amount: "1234".to_string(),
+            trace_id: format!("trace_{}", i),
+            fee_coin: None,
+            fee_amount: None,
+            event_time_us: Some(1678957200000000 + i as i64),
+            ingest_time_us: 1678957200000000,
+            duplicate: false,
+        }
+    }
+
+    #[test]
+    fn replay_stops_at_torn_write() {
+        let root = std::env::temp_dir().join(format!("wal_test_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut wal = Wal::open(root.to_str().unwrap()).unwrap();
+        wal.reset("20230316_17_1.parquet").unwrap();
+
+        let mut buf = Vec::new();
+        encode_record(&mut buf, &record(1));
+        let mut second = record(2);
+        second.fee_coin = Some("usdt".to_string());
+        second.fee_amount = Some("0.1".to_string());
+        second.duplicate = true;
+        second.kind = EventKind::Trade(Some(Trade {
+            price: "27000.5".to_string(),
+            qty: "0.01".to_string(),
+            side: Side::Sell,
+        }));
+        encode_record(&mut buf, &second);
+        wal.append(&buf).unwrap();
+        // 模拟崩溃时只写了一半的记录
+        let mut torn = Vec::new();
+        encode_record(&mut torn, &record(3));
+        wal.append(&torn[..torn.len() / 2]).unwrap();
+
+        let replay = wal.replay().unwrap();
+        assert_eq!(replay.file_name.as_deref(), Some("20230316_17_1.parquet"));
+        assert_eq!(replay.records.len(), 2);
+        assert_eq!(replay.records[1], second);
+
+        wal.clear().unwrap();
+        assert!(wal.replay().unwrap().records.is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn truncate_drops_later_records() {
+        let root = std::env::temp_dir().join(format!("wal_truncate_test_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut wal = Wal::open(root.to_str().unwrap()).unwrap();
+        wal.reset("20230316_17_1.parquet").unwrap();
+
+        let mut buf = Vec::new();
+        encode_record(&mut buf, &record(1));
+        wal.append(&buf).unwrap();
+        let size = wal.size().unwrap();
+        buf.clear();
+        encode_record(&mut buf, &record(2));
+        wal.append(&buf).unwrap();
+        wal.truncate(size).unwrap();
+        // 截断之后还能继续追加
+        buf.clear();
+        encode_record(&mut buf, &record(3));
+        wal.append(&buf).unwrap();
+
+        let replay = wal.replay().unwrap();
+        assert_eq!(replay.records, vec![record(1), record(3)]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn replay_stops_at_undecodable_record() {
+        let root = std::env::temp_dir().join(format!("wal_corrupt_test_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut wal = Wal::open(root.to_str().unwrap()).unwrap();
+        wal.reset("20230316_17_1.parquet").unwrap();
+
+        let mut buf = Vec::new();
+        encode_record(&mut buf, &record(1));
+        // 校验和正确, 但是内容不完整
+        encode_frame(&mut buf, TAG_RECORD, &[1]);
+        encode_record(&mut buf, &record(2));
+        wal.append(&buf).unwrap();
+
+        let replay = wal.replay().unwrap();
+        assert!(replay.corrupt);
+        assert_eq!(replay.records, vec![record(1)]);
+
+        let dir = root.join("quarantine");
+        wal.quarantine(dir.to_str().unwrap()).unwrap();
+        let copied = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap();
+        assert_eq!(
+            std::fs::read(copied.path()).unwrap().len() as u64,
+            wal.size().unwrap()
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
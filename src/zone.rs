@@ -0,0 +1,67 @@
+use chrono::{DateTime, Local, Offset, TimeZone, Utc};
+
+use super::error::{Error, Result};
+
+/// 文件名, s3 key 以及时间列使用的时区, 默认 UTC,
+/// 不同地区的机器写出来的分区才能对齐
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Zone {
+    #[default]
+    Utc,
+    // 机器本地时区, 会受 DST 影响
+    Local,
+    // IANA 名字, 例如 Asia/Shanghai
+    Named(chrono_tz::Tz),
+}
+
+impl Zone {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "UTC" | "utc" => Ok(Zone::Utc),
+            "Local" | "local" => Ok(Zone::Local),
+            _ => name
+                .parse::<chrono_tz::Tz>()
+                .map(Zone::Named)
+                .map_err(|e| Error::InvalidArgument(format!("timezone {} {}", name, e))),
+        }
+    }
+
+    /// now 时刻这个时区相对 UTC 的偏移, 单位秒
+    pub fn offset_secs(&self, now: DateTime<Utc>) -> i64 {
+        match self {
+            Zone::Utc => 0,
+            Zone::Local => Local
+                .offset_from_utc_datetime(&now.naive_utc())
+                .fix()
+                .local_minus_utc() as i64,
+            Zone::Named(tz) => tz
+                .offset_from_utc_datetime(&now.naive_utc())
+                .fix()
+                .local_minus_utc() as i64,
+        }
+    }
+
+    /// 写到 Timestamp 列类型里的时区, 本地时区用启动时的固定偏移表示
+    pub fn arrow_name(&self) -> String {
+        match self {
+            Zone::Utc => "UTC".to_string(),
+            Zone::Local => Local::now().format("%:z").to_string(),
+            Zone::Named(tz) => tz.name().to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn named_zone_offset() {
+        let zone = Zone::parse("Asia/Shanghai").unwrap();
+        let now = Utc.with_ymd_and_hms(2023, 3, 16, 9, 0, 0).unwrap();
+        assert_eq!(zone.offset_secs(now), 8 * 3600);
+        assert_eq!(zone.arrow_name(), "Asia/Shanghai");
+        assert_eq!(Zone::parse("UTC").unwrap().offset_secs(now), 0);
+        assert!(Zone::parse("Mars/Olympus").is_err());
+    }
+}